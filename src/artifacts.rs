@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+use crate::{package, util};
+
+/// Copies the zip of `bin` into --artifact-dir as `<bin>-<git sha>-<unix time>.zip` with its
+/// `.sha256`, points `<bin>.zip` there at it and prunes to the newest `keep`, returning the copy
+pub(crate) fn stamp(zip_path: &Path, bin: &str, dir: &Path, project_dir: &Path, keep: Option<usize>) -> PathBuf {
+    let sha = util::build_metadata(project_dir)
+        .into_iter()
+        .find(|(key, _)| *key == "GIT_SHA")
+        .map(|(_, sha)| sha.chars().take(12).collect())
+        .unwrap_or_else(|| "nogit".to_owned());
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let artifact = dir.join(format!("{}-{}-{}.zip", bin, sha, timestamp));
+
+    let copied = fs::create_dir_all(dir).and_then(|_| fs::copy(zip_path, &artifact));
+    if let Err(e) = copied {
+        eprintln!("Can't copy {} to {}: {}", zip_path.display(), artifact.display(), e);
+        process::exit(1);
+    }
+    package::write_checksum(&artifact);
+    let latest = dir.join(format!("{}.zip", bin));
+    if let Err(e) = link(&artifact, &latest) {
+        eprintln!("Warning: can't point {} at {}: {}", latest.display(), artifact.display(), e);
+    }
+    println!("Stored the zip as {}", artifact.display());
+
+    if let Some(keep) = keep {
+        prune(dir, bin, keep.max(1));
+    }
+    artifact
+}
+
+/// The artifact `<bin>.zip` in --artifact-dir points at, for deploying when the build was skipped
+pub(crate) fn latest(dir: &Path, bin: &str) -> Option<PathBuf> {
+    fs::canonicalize(dir.join(format!("{}.zip", bin))).ok()
+}
+
+/// Replaces `latest` with a relative symlink to `artifact`, or a copy where symlinks need privileges
+#[cfg(unix)]
+fn link(artifact: &Path, latest: &Path) -> io::Result<()> {
+    let mut tmp = latest.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::remove_file(&tmp).ok();
+    std::os::unix::fs::symlink(artifact.file_name().unwrap(), &tmp)?;
+    // The rename replaces the old link atomically
+    fs::rename(&tmp, latest)
+}
+
+#[cfg(not(unix))]
+fn link(artifact: &Path, latest: &Path) -> io::Result<()> {
+    fs::copy(artifact, latest).map(|_| ())
+}
+
+/// Removes all but the newest `keep` artifacts of `bin` with their `.sha256` and `.size` files
+fn prune(dir: &Path, bin: &str, keep: usize) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut artifacts: Vec<(u64, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            parse_timestamp(&name, bin).map(|timestamp| (timestamp, entry.path()))
+        })
+        .collect();
+    artifacts.sort();
+    let stale = artifacts.len().saturating_sub(keep);
+    for (_, path) in artifacts.into_iter().take(stale) {
+        match fs::remove_file(&path) {
+            Ok(()) => println!("Pruned {}", path.display()),
+            Err(e) => eprintln!("Warning: can't remove {}: {}", path.display(), e),
+        }
+        for suffix in &[".sha256", ".size"] {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(suffix);
+            fs::remove_file(sidecar).ok();
+        }
+    }
+}
+
+/// The timestamp of `<bin>-<git sha>-<unix time>.zip`, none for other files (e.g. of `<bin>-admin`)
+fn parse_timestamp(name: &str, bin: &str) -> Option<u64> {
+    let stamp = name.strip_prefix(bin)?.strip_prefix('-')?.strip_suffix(".zip")?;
+    let (sha, timestamp) = stamp.split_once('-')?;
+    let sha_like = sha == "nogit" || sha.chars().all(|c| c.is_ascii_hexdigit());
+    if !sha_like || timestamp.is_empty() || !timestamp.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    timestamp.parse().ok()
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+use crate::exit;
+use crate::util::{self, IncludeConfig};
+
+/// A pattern of `include` in Lambda.toml and the directory in the zip its matches go to
+struct Include {
+    pattern: String,
+    to: Option<String>,
+}
+
+/// Adds the files matching `include` in Lambda.toml to the zip, next to the bootstrap. Each entry is
+/// a glob relative to the project root, or a `{ from = "<glob>", to = "<dir in zip>" }` table.
+pub(crate) fn add(zip_path: &Path, project_dir: &Path) {
+    let files = files(project_dir);
+    if files.is_empty() {
+        return;
+    }
+
+    // Appended to a copy that replaces the zip, so a failure doesn't leave a broken zip behind
+    let tmp_path = zip_path.with_extension("zip.tmp");
+    let result = fs::copy(zip_path, &tmp_path).and_then(|_| append(&tmp_path, &files));
+    if let Err(e) = result.and_then(|_| fs::rename(&tmp_path, zip_path)) {
+        fs::remove_file(&tmp_path).ok();
+        eprintln!("Can't add the included files to {}: {}", zip_path.display(), e);
+        process::exit(1);
+    }
+
+    println!("Included in {}:", zip_path.display());
+    let mut total = 0;
+    for (file, name) in &files {
+        let size = fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0);
+        total += size;
+        println!("  {:<50} {:>10} bytes", name, size);
+    }
+    println!("  {} files, {} bytes", files.len(), total);
+}
+
+/// The files matching `include` in Lambda.toml with their names in the zip
+pub(crate) fn files(project_dir: &Path) -> Vec<(PathBuf, String)> {
+    let includes = read_includes();
+    let mut files: Vec<(PathBuf, String)> = Vec::new();
+    let mut names = HashSet::new();
+    for include in &includes {
+        let pattern = project_dir.join(&include.pattern);
+        let matches: Vec<PathBuf> = match glob::glob(&pattern.to_string_lossy()) {
+            Ok(paths) => paths.filter_map(Result::ok).collect(),
+            Err(e) => {
+                eprintln!(
+                    "Invalid pattern {} in include in {}: {}",
+                    include.pattern,
+                    util::lambda_toml_name(),
+                    e
+                );
+                process::exit(exit::BAD_ARGS);
+            }
+        };
+        if matches.is_empty() {
+            println!(
+                "Warning: {} in include in {} matches nothing",
+                include.pattern,
+                util::lambda_toml_name()
+            );
+        }
+
+        let base = project_dir.join(base_dir(&include.pattern));
+        let to = include.to.clone().unwrap_or_else(|| path_name(&base_dir(&include.pattern)));
+        for path in matches {
+            for file in walk(&path) {
+                let relative = file.strip_prefix(&base).unwrap_or(&file);
+                let name = [to.as_str(), path_name(relative).as_str()]
+                    .iter()
+                    .filter(|part| !part.is_empty())
+                    .map(|part| part.trim_matches('/'))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if name == "bootstrap" {
+                    eprintln!(
+                        "{} in include in {} would replace the bootstrap",
+                        file.display(),
+                        util::lambda_toml_name()
+                    );
+                    process::exit(exit::BAD_ARGS);
+                }
+                if names.insert(name.clone()) {
+                    files.push((file, name));
+                }
+            }
+        }
+    }
+    files
+}
+
+fn append(zip_path: &Path, files: &[(PathBuf, String)]) -> io::Result<()> {
+    let zip_file = fs::OpenOptions::new().read(true).write(true).open(zip_path)?;
+    let mut zip = ZipWriter::new_append(zip_file)?;
+    for (file, name) in files {
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(file_mode(file));
+        zip.start_file(name.as_str(), options)?;
+        io::copy(&mut File::open(file)?, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn read_includes() -> Vec<Include> {
+    util::lambda_toml()
+        .include
+        .iter()
+        .map(|entry| match entry {
+            IncludeConfig::Pattern(pattern) => Include {
+                pattern: pattern.clone(),
+                to: None,
+            },
+            IncludeConfig::Mapped(mapped) => Include {
+                pattern: mapped.from.clone(),
+                to: mapped.to.clone(),
+            },
+        })
+        .collect()
+}
+
+/// The part of the pattern before the first component with a wildcard, or the directory of a plain
+/// path, which the matches keep their paths relative to
+fn base_dir(pattern: &str) -> PathBuf {
+    let path = Path::new(pattern);
+    let has_wildcard = |component: &Component| {
+        component.as_os_str().to_string_lossy().contains(|c| "*?[".contains(c))
+    };
+    if !path.components().any(|component| has_wildcard(&component)) {
+        return path.parent().map(Path::to_path_buf).unwrap_or_default();
+    }
+    path.components().take_while(|component| !has_wildcard(component)).collect()
+}
+
+/// The files under `path` (just `path` when it's a file), skipping symlinks with a warning
+fn walk(path: &Path) -> Vec<PathBuf> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Vec::new(),
+    };
+    if metadata.file_type().is_symlink() {
+        println!("Warning: not including symlink {}", path.display());
+        return Vec::new();
+    }
+    if !metadata.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    entries.sort();
+    entries.iter().flat_map(|entry| walk(entry)).collect()
+}
+
+/// The path with forward slashes as zip entry names have them
+fn path_name(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(unix)]
+fn file_mode(file: &Path) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(file).map(|metadata| metadata.permissions().mode() & 0o777).unwrap_or(0o644)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_file: &Path) -> u32 {
+    0o644
+}
@@ -0,0 +1,94 @@
+use futures::Future;
+use rusoto_core::credential::{
+    AwsCredentials, ChainProvider, CredentialsError, InstanceMetadataProvider, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::request::DispatchSignedRequest;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, Region};
+use std::process;
+use std::str::FromStr;
+use crate::{exit, http, AwsOpt};
+
+/// Creates a rusoto client with the credentials picked from the options, e.g.
+/// `aws::client(opt, region, LambdaClient::new_with_client)`
+pub(crate) fn client<C>(opt: &AwsOpt, region: &str, new_with_client: fn(Client, Region) -> C) -> C {
+    let dispatcher = http::dispatcher(opt);
+    let region = Region::from_str(region).unwrap_or_else(|e| {
+        eprintln!("Invalid region {}: {}", region, e);
+        process::exit(exit::BAD_ARGS);
+    });
+
+    if opt.instance_role {
+        return new_with_client(Client::new_with(InstanceMetadataProvider::new(), dispatcher), region);
+    }
+
+    let client = match (&opt.access_key, &opt.secret_key, &opt.profile) {
+        (Some(access_key), Some(secret_key), _) => {
+            let creds = StaticProvider::new_minimal(access_key.to_owned(), secret_key.to_owned());
+            Client::new_with(creds, dispatcher)
+        },
+        (_, _, Some(profile)) => {
+            let mut creds = ProfileProvider::new().unwrap_or_else(|e| {
+                eprintln!("Can't load AWS profiles: {}", e);
+                process::exit(exit::CREDENTIALS);
+            });
+            creds.set_profile(profile.to_owned());
+            Client::new_with(creds, dispatcher)
+        },
+        _ => Client::new_with(ChainProvider::new(), dispatcher),
+    };
+    new_with_client(client, region)
+}
+
+/// Signs and sends a request to an API of `service` (e.g. lambda) that this version of rusoto
+/// lacks, returning the status and body. The path may end in a `?key=value&...` query.
+pub(crate) fn send(
+    opt: &AwsOpt,
+    service: &str,
+    region: &str,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<(u16, Vec<u8>), String> {
+    let region = Region::from_str(region).map_err(|e| e.to_string())?;
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let mut req = SignedRequest::new(method, service, &region, path);
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        req.add_param(key, value);
+    }
+    req.set_content_type("application/x-amz-json-1.1".to_owned());
+    if let Some(body) = body {
+        req.set_payload(Some(body.to_string().into_bytes()));
+    }
+
+    let creds = credentials(opt).map_err(|e| format!("Can't load AWS credentials: {}", e))?;
+    req.sign_with_plus(&creds, true);
+    let res = http::dispatcher(opt)
+        .dispatch(req, None)
+        .and_then(|res| res.buffer())
+        .wait()
+        .map_err(|e| e.to_string())?;
+    Ok((res.status.as_u16(), res.body.to_vec()))
+}
+
+/// Credentials picked like in `client`
+fn credentials(opt: &AwsOpt) -> Result<AwsCredentials, CredentialsError> {
+    if opt.instance_role {
+        return InstanceMetadataProvider::new().credentials().wait();
+    }
+    match (&opt.access_key, &opt.secret_key, &opt.profile) {
+        (Some(access_key), Some(secret_key), _) => {
+            StaticProvider::new_minimal(access_key.to_owned(), secret_key.to_owned())
+                .credentials()
+                .wait()
+        }
+        (_, _, Some(profile)) => {
+            let mut creds = ProfileProvider::new()?;
+            creds.set_profile(profile.to_owned());
+            creds.credentials().wait()
+        }
+        _ => ChainProvider::new().credentials().wait(),
+    }
+}
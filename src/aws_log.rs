@@ -0,0 +1,201 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+
+/// Prints rusoto's debug logging of the AWS requests and responses to stderr, with credentials
+/// redacted
+struct AwsLogger;
+
+static LOGGER: AwsLogger = AwsLogger;
+
+/// Values read from Secrets Manager, redacted wherever they show up, e.g. in request bodies
+static MASKED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Header and query parameter names whose values would let the reader act with the credentials
+const SECRETS: &[&str] = &[
+    "authorization",
+    "x-amz-security-token",
+    "x-amz-signature",
+    "x-amz-credential",
+    "signature",
+    "credential",
+];
+
+impl Log for AwsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug && metadata.target().starts_with("rusoto")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let message = redact(&record.args().to_string());
+            eprintln!("[{} {}] {}", record.level(), record.target(), message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Turns on the logging for --debug-aws
+pub(crate) fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
+
+/// Redacts these values from the logging from now on, also in their escaped form within JSON
+/// request bodies, e.g. of secrets holding JSON
+pub(crate) fn mask_values(values: Vec<String>) {
+    let mut masked = MASKED.lock().unwrap();
+    for value in values.into_iter().filter(|value| !value.is_empty()) {
+        let json = serde_json::to_string(&value).unwrap();
+        let escaped = &json[1..json.len() - 1];
+        if escaped != value {
+            masked.push(escaped.to_owned());
+        }
+        masked.push(value);
+    }
+}
+
+/// Replaces the values of secret headers (`Authorization: ...`) and parameters (`Signature=...`),
+/// and the values given to `mask_values`
+fn redact(message: &str) -> String {
+    let mut message = message.lines().map(redact_line).collect::<Vec<_>>().join("\n");
+    for value in MASKED.lock().unwrap().iter() {
+        message = message.replace(value.as_str(), "<redacted>");
+    }
+    message
+}
+
+fn redact_line(line: &str) -> String {
+    let lower = line.to_ascii_lowercase();
+    let mut values = Vec::new();
+    for secret in SECRETS {
+        for (i, _) in lower.match_indices(secret) {
+            if let Some(span) = value_span(&lower, i + secret.len(), *secret == "authorization") {
+                values.push(span);
+            }
+        }
+    }
+
+    // Overlapping values, e.g. a signature within an authorization header, are redacted as one
+    values.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in values {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    let mut redacted = line.to_owned();
+    for (start, end) in merged.into_iter().rev() {
+        redacted.replace_range(start..end, "<redacted>");
+    }
+    redacted
+}
+
+/// The span of the value after a secret name ending at `from`, e.g. `authorization: ...`,
+/// `x-amz-security-token:"..."` (rusoto's headers), `"x-amz-security-token": [[73, 81, ...]]`
+/// (header bytes) or `Signature=...`. None when the name isn't followed by a value.
+fn value_span(lower: &str, from: usize, to_line_end: bool) -> Option<(usize, usize)> {
+    let rest = &lower[from..];
+    // The closing quote of a quoted name
+    let rest = rest.strip_prefix('"').unwrap_or(rest);
+    let value = match rest.chars().next() {
+        Some(':') => rest[1..].trim_start(),
+        Some('=') => &rest[1..],
+        _ => return None,
+    };
+    let start = lower.len() - value.len();
+
+    let end = if value.starts_with('[') {
+        // Byte arrays of the values, e.g. `[[73, 81], [74]]`
+        value.find("]]").map(|end| end + 2).or_else(|| value.find(']').map(|end| end + 1))
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        // Debug formatted values are quoted, redact all of it including the quotes
+        quoted.find('"').map(|end| end + 2)
+    } else if to_line_end {
+        // Authorization values go on after the scheme, e.g. `AWS4-HMAC-SHA256 Credential=...`
+        None
+    } else {
+        // Escaped newlines (`\n`) end the values within debug formatted canonical requests
+        value.find(|c: char| "&,\"\\".contains(c) || c.is_whitespace())
+    };
+    let end = start + end.unwrap_or(value.len());
+    if end > start {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "IQoJb3JpZ2luX2VjEJr//////////wEaCXVzLWVhc3QtMSJHMEUCIQ";
+
+    fn assert_redacted(line: &str, secret: &str) {
+        let redacted = redact_line(line);
+        assert!(!redacted.contains(secret), "{} leaks {}", redacted, secret);
+        assert!(redacted.contains("<redacted>"), "{}", redacted);
+    }
+
+    #[test]
+    fn redacts_quoted_headers() {
+        assert_redacted(&format!("x-amz-security-token:\"{}\"", TOKEN), TOKEN);
+        assert_redacted(
+            "authorization:\"AWS4-HMAC-SHA256 Credential=AKIAEXAMPLE/20191010/eu-west-1/lambda/aws4_request, SignedHeaders=host, Signature=f00d\"",
+            "AKIAEXAMPLE",
+        );
+    }
+
+    #[test]
+    fn redacts_canonical_headers() {
+        assert_redacted(
+            &format!("canonical_headers: \"host:lambda.eu-west-1.amazonaws.com\\nx-amz-security-token:{}\\n\"", TOKEN),
+            TOKEN,
+        );
+        let bytes: Vec<String> = TOKEN.bytes().map(|b| b.to_string()).collect();
+        let line = format!(
+            "canonical_headers: {{\"host\": [[104, 111]], \"x-amz-security-token\": [[{}]]}}",
+            bytes.join(", ")
+        );
+        let redacted = redact_line(&line);
+        assert!(!redacted.contains(&bytes[..4].join(", ")), "{}", redacted);
+        assert!(redacted.contains("\"host\": [[104, 111]]"), "{}", redacted);
+    }
+
+    #[test]
+    fn redacts_query_parameters() {
+        let line = format!(
+            "final_uri: https://s3.amazonaws.com/b/k?X-Amz-Credential=AKIAEXAMPLE%2F20191010&X-Amz-Security-Token={}&X-Amz-Signature=f00d",
+            TOKEN
+        );
+        let redacted = redact_line(&line);
+        for secret in &["AKIAEXAMPLE", TOKEN, "f00d"] {
+            assert!(!redacted.contains(secret), "{}", redacted);
+        }
+    }
+
+    #[test]
+    fn masks_values_in_json_bodies() {
+        let secret = "{\"password\":\"hunter2\\\\\"\nx\"}";
+        mask_values(vec![secret.to_owned()]);
+        let body = serde_json::json!({ "Environment": { "Variables": { "DB": secret } } });
+        let redacted = redact(&format!(" payload: {}", body));
+        assert!(!redacted.contains("hunter2"), "{}", redacted);
+        assert!(redacted.contains("<redacted>"), "{}", redacted);
+        assert!(!redact(secret).contains("hunter2"));
+    }
+
+    #[test]
+    fn keeps_other_lines() {
+        for line in &[
+            "signed_headers: \"content-type;host;x-amz-date;x-amz-security-token\"",
+            "content-type:\"application/x-amz-json-1.1\"",
+            "Response status: 200 OK",
+        ] {
+            assert_eq!(redact_line(line), *line);
+        }
+    }
+}
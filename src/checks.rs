@@ -0,0 +1,57 @@
+use std::process::{self, Command};
+use std::path::Path;
+use std::time::Instant;
+use crate::Opt;
+use crate::util::{self, CommandExt};
+
+/// Runs the --check and Lambda.toml `checks` commands in the project directory before building,
+/// exiting after a summary when one fails
+pub(crate) fn run(project_dir: &Path, opt: &Opt) {
+    let mut checks = util::lambda_toml().checks.clone();
+    checks.extend(opt.checks.iter().cloned());
+    if checks.is_empty() {
+        return;
+    }
+    if opt.skip_checks {
+        eprintln!("\n!!!!! WARNING: skipping checks: {} !!!!!\n", checks.join(", "));
+        return;
+    }
+
+    let mut results = Vec::new();
+    for check in &checks {
+        println!("===== Running check {} =====", check);
+        let started = Instant::now();
+        let passed = command(check).current_dir(project_dir).status_bool();
+        results.push((check, passed, started.elapsed()));
+        if !passed {
+            break;
+        }
+    }
+
+    println!("\n===== Checks =====");
+    for (check, passed, duration) in &results {
+        let status = if *passed { "passed" } else { "FAILED" };
+        println!("{:<30} {} in {:.1} s", check, status, duration.as_secs_f64());
+    }
+    if results.iter().any(|(_, passed, _)| !passed) {
+        eprintln!("Check failed, not deploying (--skip-checks skips them in emergencies)");
+        process::exit(1);
+    }
+}
+
+/// The built-in `clippy` and `audit` checks, anything else is run with the shell
+fn command(check: &str) -> Command {
+    match check {
+        "clippy" => {
+            let mut cmd = Command::new("cargo");
+            cmd.args(["clippy", "--", "-D", "warnings"]);
+            cmd
+        }
+        "audit" => {
+            let mut cmd = Command::new("cargo");
+            cmd.arg("audit");
+            cmd
+        }
+        _ => util::shell_command(check),
+    }
+}
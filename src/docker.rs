@@ -1,89 +1,1880 @@
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, IsTerminal};
 use std::ffi::OsStr;
-use std::path::Path;
-use crate::Opt;
-use crate::util::CommandExt;
-use std::process;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use crate::{progress, Opt};
+use crate::util::{self, CommandExt};
+use crate::exit;
+use std::{process, thread};
 
-pub(crate) fn build_args(project_dir: &Path, cargo_registry: &Path, opt: &Opt) -> Vec<String> {
+/// Runtime and name of the container to kill on Ctrl+C
+static RUNNING_CONTAINER: Mutex<Option<(String, String)>> = Mutex::new(None);
+static CTRLC_HANDLER: Once = Once::new();
+
+/// Build image when none is given or picked for the function's runtime
+pub(crate) const DEFAULT_IMAGE: &str = "softprops/lambda-rust:latest";
+/// Build images for function runtimes, binaries linked against al2's glibc run on al2023 too
+pub(crate) const RUNTIME_IMAGES: &[(&str, &str)] = &[
+    ("provided.al2", DEFAULT_IMAGE),
+    ("provided.al2023", DEFAULT_IMAGE),
+];
+/// Docker errors that are worth retrying, e.g. network hiccups while pulling the image
+const TRANSIENT_ERRORS: &[&str] = &[
+    "TLS handshake timeout",
+    "error pulling image configuration",
+    "i/o timeout",
+    "connection reset by peer",
+    "connection refused",
+    "Client.Timeout exceeded",
+    "net/http: request canceled",
+    "unexpected EOF",
+    "toomanyrequests",
+    "503 Service Unavailable",
+    "502 Bad Gateway",
+    "device or resource busy",
+];
+/// Output of a failed compilation, which no retry fixes
+const COMPILER_ERRORS: &[&str] = &["could not compile", "error[E", "error: aborting"];
+/// Host keys of github.com, so the first ssh fetch doesn't stop at a host key prompt
+const GITHUB_KNOWN_HOSTS: &str = "\
+github.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl
+github.com ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBEmKSENjQEezOmxkZMy7opKgwFB9nkt5YRrYMjNuG5N87uRgg6CLrbo5wAdT/y6v0mKV0U2w0WZ2YB/++Tpockg=
+";
+/// Docker Desktop's path of the host's SSH agent socket on macOS
+const DOCKER_DESKTOP_SSH_SOCK: &str = "/run/host-services/ssh-auth.sock";
+/// Label of the containers created by us, used to find them for --gc-containers
+const LABEL: &str = "cargo-aws-lambda";
+/// Volume holding the sccache cache on remote daemons, which can't see the host directory
+const SCCACHE_VOLUME: &str = "cargo-aws-lambda-sccache";
+/// Host environment variables sccache uses for S3-backed caches
+const SCCACHE_ENV_VARS: &[&str] = &[
+    "SCCACHE_BUCKET",
+    "SCCACHE_REGION",
+    "SCCACHE_ENDPOINT",
+    "SCCACHE_S3_KEY_PREFIX",
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+];
+
+/// What the `docker run` arguments depend on besides the options and paths: the container
+/// runtime's features and the terminal
+struct Host {
+    /// `docker run --mount`, older runtimes only have `-v`
+    supports_mount: bool,
+    /// `docker run --platform`
+    supports_platform: bool,
+    /// arm64 images run natively or under qemu
+    can_run_arm64: bool,
+    /// The runtime takes windows paths, also docker.exe called from WSL
+    windows: bool,
+    stdout_terminal: bool,
+    stdin_terminal: bool,
+    color: bool,
+    columns: String,
+}
+
+impl Host {
+    fn probe(opt: &Opt) -> Host {
+        static RUN_HELP: OnceLock<String> = OnceLock::new();
+        let run_help = RUN_HELP.get_or_init(|| {
+            Command::new(&opt.container_runtime)
+                .args(["run", "--help"])
+                .output()
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+                .unwrap_or_default()
+        });
+        Host {
+            supports_mount: run_help.contains("--mount"),
+            // Docker versions before 20.10 don't know it without experimental features enabled
+            supports_platform: run_help.contains("--platform"),
+            can_run_arm64: can_run_arm64(),
+            windows: std::env::consts::OS == "windows" || opt.container_runtime.ends_with(".exe"),
+            stdout_terminal: io::stdout().is_terminal(),
+            stdin_terminal: io::stdin().is_terminal(),
+            color: util::color_enabled(opt),
+            columns: std::env::var("COLUMNS").unwrap_or_else(|_| "120".to_owned()),
+        }
+    }
+
+    /// Arguments bind mounting `path` at `target`
+    fn mount(&self, path: &Path, target: &str) -> [String; 2] {
+        mount_args(&mount_path(path, self.windows), target, self.supports_mount)
+    }
+}
+
+pub(crate) fn build_args(
+    project_dir: &Path,
+    cargo_home: Option<&Path>,
+    container: &str,
+    target: Option<&str>,
+    opt: &Opt,
+) -> Vec<String> {
+    create_mount_dirs(project_dir, cargo_home, opt);
+    run_args(project_dir, cargo_home, container, target, docker_cmd(opt), &Host::probe(opt), opt)
+}
+
+/// The --docker-cmd (or docker_cmd in Lambda.toml) to run instead of the image's build script
+fn docker_cmd(opt: &Opt) -> Option<Vec<String>> {
+    if opt.docker_cmd.is_empty() {
+        None
+    } else {
+        Some(opt.docker_cmd.clone())
+    }
+}
+
+/// Creates the host directories `run_args` mounts, which the runtime would create owned by root
+fn create_mount_dirs(project_dir: &Path, cargo_home: Option<&Path>, opt: &Opt) {
+    let target_dir = util::target_dir(project_dir);
+    let mut dirs = Vec::new();
+    if target_dir != project_dir.join("target") {
+        dirs.push(target_dir.clone());
+    }
+    let mut excluded = opt.exclude.iter().filter_map(|dir| exclude_dir(dir).ok());
+    if excluded.any(|dir| Path::new("target/lambda").starts_with(dir)) {
+        dirs.push(target_dir.join("lambda"));
+    }
+    if let (false, Some(cargo_home)) = (opt.use_build_volume, cargo_home) {
+        dirs.push(cargo_home.join("git"));
+    }
+    if opt.sccache {
+        dirs.push(sccache_dir(opt));
+    }
+    for dir in dirs {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            eprintln!("Can't create {}: {}", dir.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Arguments for `docker run`, running `command` instead of the image's own when given
+fn run_args(
+    project_dir: &Path,
+    cargo_home: Option<&Path>,
+    container: &str,
+    target: Option<&str>,
+    command: Option<Vec<String>>,
+    host: &Host,
+    opt: &Opt,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec!["run".into()];
+    if !opt.keep_container {
+        args.push("--rm".into());
+    }
+    args.extend(vec![
+        "--name".into(),
+        container.into(),
+        "--label".into(),
+        format!("{}=1", LABEL),
+    ]);
+    args.extend(host.mount(project_dir, "/code"));
+
+    // The container always builds into /code/target, point it at a relocated target directory
+    let target_dir = util::target_dir(project_dir);
+    let custom_target = target_dir != project_dir.join("target");
+    if custom_target {
+        args.extend(host.mount(&target_dir, "/code/target"));
+        // Overrides a build.target-dir from the mounted cargo config
+        args.push("-e".into());
+        args.push("CARGO_TARGET_DIR=/code/target".into());
+    }
+
+    // Checked by `check_excludes` before building
+    for dir in opt.exclude.iter().filter_map(|dir| exclude_dir(dir).ok()) {
+        // An anonymous volume over the directory hides the host contents from the container
+        if custom_target && dir == "target" {
+            continue;
+        }
+        args.push("-v".into());
+        args.push(format!("/code/{}", dir));
+
+        // The zip must still end up on the host
+        if Path::new("target/lambda").starts_with(&dir) {
+            args.extend(host.mount(&target_dir.join("lambda"), "/code/target/lambda"));
+        }
+    }
+
+    if opt.use_build_volume {
+        push_build_volume_args(&mut args, opt);
+    } else if let Some(cargo_home) = cargo_home {
+        args.extend(host.mount(&cargo_home.join("registry"), "/root/.cargo/registry"));
+        args.extend(host.mount(&cargo_home.join("git"), "/root/.cargo/git"));
+    }
+
+    if opt.sccache {
+        args.extend(host.mount(&sccache_dir(opt), "/sccache"));
+    }
+
+    push_git_auth_args(&mut args, host, opt);
+    push_container_args(&mut args, target, command, host, opt);
+    args
+}
+
+/// Mounts the SSH agent socket and known_hosts for --ssh-agent, and the credentials file for
+/// --git-credentials
+fn push_git_auth_args(args: &mut Vec<String>, host: &Host, opt: &Opt) {
+    if opt.ssh_agent {
+        // Docker Desktop can't mount sockets from the macOS host, it forwards the agent itself
+        let socket = if std::env::consts::OS == "macos" {
+            PathBuf::from(DOCKER_DESKTOP_SSH_SOCK)
+        } else {
+            match std::env::var_os("SSH_AUTH_SOCK") {
+                Some(socket) => PathBuf::from(socket),
+                None => {
+                    eprintln!("--ssh-agent needs a running SSH agent, SSH_AUTH_SOCK isn't set");
+                    process::exit(exit::BAD_ARGS);
+                }
+            }
+        };
+        args.extend(host.mount(&socket, "/ssh-agent.sock"));
+        args.push("-e".into());
+        args.push("SSH_AUTH_SOCK=/ssh-agent.sock".into());
+        args.extend(readonly(host.mount(&known_hosts(), "/root/.ssh/known_hosts")));
+    }
+
+    if let Some(ref credentials) = opt.git_credentials {
+        if !credentials.is_file() {
+            eprintln!("Git credentials file {} doesn't exist", credentials.display());
+            process::exit(exit::BAD_ARGS);
+        }
+        let credentials = credentials.canonicalize().expect("Can't resolve git credentials path");
+        args.extend(readonly(host.mount(&credentials, "/root/.git-credentials")));
+        // Cargo's own git client doesn't read .git-credentials, the git CLI does with the store helper
+        for env in &[
+            "CARGO_NET_GIT_FETCH_WITH_CLI=true",
+            "GIT_CONFIG_COUNT=1",
+            "GIT_CONFIG_KEY_0=credential.helper",
+            "GIT_CONFIG_VALUE_0=store",
+        ] {
+            args.push("-e".into());
+            args.push((*env).into());
+        }
+    }
+}
+
+/// Writes the known_hosts for the build container, github.com and the host user's known hosts
+fn known_hosts() -> PathBuf {
+    let home = std::env::var_os("HOME").expect("Missing HOME");
+    let mut dir = PathBuf::from(&home);
+    dir.extend(&[".cache", "cargo-aws-lambda"]);
+    fs::create_dir_all(&dir).expect("Can't create cache directory");
+
+    let mut contents = GITHUB_KNOWN_HOSTS.to_owned();
+    let user_known_hosts = PathBuf::from(&home).join(".ssh").join("known_hosts");
+    if let Ok(user) = fs::read_to_string(user_known_hosts) {
+        contents.push_str(&user);
+    }
+    let path = dir.join("known_hosts");
+    fs::write(&path, contents).expect("Can't write known_hosts");
+    path
+}
+
+/// Makes a `bind_mount` read-only
+fn readonly(mut mount: [String; 2]) -> [String; 2] {
+    let suffix = if mount[0] == "-v" { ":ro" } else { ",readonly" };
+    mount[1].push_str(suffix);
+    mount
+}
+
+/// Arguments for running `cargo test --release` with the same mounts and environment as the build
+pub(crate) fn test_args(
+    project_dir: &Path,
+    cargo_home: Option<&Path>,
+    container: &str,
+    target: Option<&str>,
+    opt: &Opt,
+) -> Vec<String> {
+    let mut command: Vec<String> = vec!["cargo".into(), "test".into(), "--release".into()];
+    if let Some(target) = target {
+        command.push("--target".into());
+        command.push(target.to_owned());
+    }
+    command.extend(util::cargo_args(opt));
+    if !opt.test_args.is_empty() {
+        command.push("--".into());
+        command.extend(opt.test_args.iter().cloned());
+    }
+    create_mount_dirs(project_dir, cargo_home, opt);
+    run_args(project_dir, cargo_home, container, target, Some(command), &Host::probe(opt), opt)
+}
+
+/// Arguments for `docker create` when the project is copied into the container instead of mounted
+pub(crate) fn create_args(container: &str, target: Option<&str>, opt: &Opt) -> Vec<String> {
     let mut args: Vec<String> = vec![
-        "run".into(),
-        "--rm".into(),
-        "-v".into(),
-        format!("{}:/code", project_dir.display()),
+        "create".into(),
+        "--name".into(),
+        container.into(),
+        "--label".into(),
+        format!("{}=1", LABEL),
     ];
 
+    // Named volumes live on the daemon, so they work with remote daemons too
     if opt.use_build_volume {
+        push_build_volume_args(&mut args, opt);
+    }
+    if opt.sccache {
         args.push("-v".into());
-        args.push(format!("{}:/build-volume", build_volume_name()));
-        args.push("-v".into());
-        args.push(format!("{}:/root/.cargo/registry", build_volume_name()));
-    } else {
-        args.push("-v".into());
-        args.push(format!(
-            "{}:/root/.cargo/registry",
-            cargo_registry.display()
-        ));
+        args.push(format!("{}:/sccache", SCCACHE_VOLUME));
+    }
+
+    if opt.ssh_agent || opt.git_credentials.is_some() {
+        eprintln!(
+            "Warning: --ssh-agent and --git-credentials need bind mounts, which a remote daemon \
+             doesn't see. Pass --force-mount if it shares the filesystem."
+        );
     }
 
+    push_container_args(&mut args, target, docker_cmd(opt), &Host::probe(opt), opt);
+    args
+}
+
+/// Mounts the build artifacts, the cargo registry and the cargo git checkouts each from a volume of
+/// their own, so they don't stomp on each other
+fn push_build_volume_args(args: &mut Vec<String>, opt: &Opt) {
+    let [target, registry, git] = build_volumes(opt);
+    args.push("-v".into());
+    args.push(format!("{}:/build-volume", target));
+    args.push("-v".into());
+    args.push(format!("{}:/root/.cargo/registry", registry));
+    args.push("-v".into());
+    args.push(format!("{}:/root/.cargo/git", git));
+}
+
+fn push_container_args(
+    args: &mut Vec<String>,
+    target: Option<&str>,
+    command: Option<Vec<String>>,
+    host: &Host,
+    opt: &Opt,
+) {
     if opt.keep_debug_info {
         args.push("-e".into());
         args.push("DEBUGINFO=1".into());
     }
 
+    let platform = match (&opt.docker_platform, target) {
+        (Some(platform), _) => Some(platform.as_str()),
+        (None, Some(target)) if target.starts_with("aarch64") => {
+            if host.can_run_arm64 {
+                Some("linux/arm64")
+            } else {
+                None
+            }
+        }
+        (None, _) => Some("linux/amd64"),
+    };
+    if let Some(platform) = platform {
+        if host.supports_platform {
+            args.push("--platform".into());
+            args.push(platform.into());
+        }
+    }
+
+    if let Some(ref memory) = opt.build_memory {
+        args.push("--memory".into());
+        args.push(memory.clone());
+    }
+    if let Some(cpus) = opt.build_cpus {
+        args.push("--cpus".into());
+        args.push(cpus.to_string());
+    }
+
+    if opt.offline {
+        // Nothing gets downloaded behind --offline's back
+        args.push("--network".into());
+        args.push("none".into());
+    }
+
+    // Cargo doesn't see our terminal in the container, and colors would be junk in piped logs
+    let color = if host.color { "always" } else { "never" };
+    args.push("-e".into());
+    args.push(format!("CARGO_TERM_COLOR={}", color));
+    if host.stdout_terminal {
+        args.push("-t".into());
+        // Attaching stdin puts the terminal into raw mode, which only works when docker writes the
+        // output itself
+        if opt.raw_build_output && host.stdin_terminal {
+            args.push("-i".into());
+        }
+        if !opt.raw_build_output {
+            // The build progress is condensed into our own counter, cargo's bar would garble it
+            args.push("-e".into());
+            args.push("CARGO_TERM_PROGRESS_WHEN=never".into());
+        }
+        args.push("-e".into());
+        args.push(format!("COLUMNS={}", host.columns));
+    }
+
+    // Set on the runtime's process by bin_env
+    if opt.bin.len() == 1 {
+        args.push("-e".into());
+        args.push("BIN".into());
+    }
+
+    // The image builds without --release and zips from target/<PROFILE> for other profiles
+    if opt.profile_name != "release" {
+        args.push("-e".into());
+        args.push(format!("PROFILE={}", util::profile_dir(&opt.profile_name)));
+    }
+
+    // The image passes CARGO_FLAGS on to cargo build
+    let mut cargo_flags = Vec::new();
+    if util::profile_dir(&opt.profile_name) == opt.profile_name && opt.profile_name != "release" {
+        // A custom profile, the image only knows release and dev
+        cargo_flags.push("--profile".to_owned());
+        cargo_flags.push(opt.profile_name.clone());
+    }
+    if let Some(target) = target {
+        cargo_flags.push("--target".to_owned());
+        cargo_flags.push(target.to_owned());
+    }
+    for arg in util::cargo_args(opt) {
+        // The image splits CARGO_FLAGS on whitespace, quoting doesn't survive that
+        if arg.contains(char::is_whitespace) {
+            eprintln!("Warning: cargo flag {:?} contains whitespace and will be split", arg);
+        }
+        cargo_flags.push(arg);
+    }
+    if !cargo_flags.is_empty() {
+        args.push("-e".into());
+        args.push(format!("CARGO_FLAGS={}", cargo_flags.join(" ")));
+    }
+
+    if opt.sccache {
+        args.push("-e".into());
+        args.push("RUSTC_WRAPPER=sccache".into());
+        args.push("-e".into());
+        args.push("SCCACHE_DIR=/sccache".into());
+        // Passed through from the host for S3-backed caches
+        for var in SCCACHE_ENV_VARS {
+            if std::env::var_os(var).is_some() {
+                args.push("-e".into());
+                args.push((*var).into());
+            }
+        }
+    }
+
+    if let Some(ref env_file) = opt.env_file {
+        args.push("--env-file".into());
+        args.push(env_file.display().to_string());
+    }
+
     for env in &opt.env {
         args.push("-e".into());
         args.push(env.clone());
     }
 
+    args.extend(opt.docker_args.iter().cloned());
+
+    let toolchain = match opt.toolchain {
+        Some(ref toolchain) => toolchain,
+        None => {
+            if let Some(mut command) = command {
+                args.push("--entrypoint".into());
+                args.push(command.remove(0));
+                args.push(opt.docker_image.clone());
+                args.extend(command);
+            } else {
+                args.push(opt.docker_image.clone());
+            }
+            return;
+        }
+    };
+
+    // The image ships its own toolchain, install the pinned one before running the command
+    let mut install = "rustup toolchain install \"$RUSTUP_TOOLCHAIN\" --profile minimal".to_owned();
+    if let Some(target) = target {
+        install.push_str(&format!(" --target {}", target));
+    }
+    args.push("-e".into());
+    args.push(format!("RUSTUP_TOOLCHAIN={}", toolchain));
+    args.push("--entrypoint".into());
+    args.push("sh".into());
     args.push(opt.docker_image.clone());
-    args
+    args.push("-c".into());
+    args.push(format!("{} && exec \"$@\"", install));
+    args.push("sh".into());
+    args.extend(command.unwrap_or_else(|| image_command(opt)));
 }
 
-pub fn manage_build_volume() {
-    let name = build_volume_name();
+/// The entrypoint and command the image runs by default
+fn image_command(opt: &Opt) -> Vec<String> {
+    let output = Command::new(&opt.container_runtime)
+        .args(["image", "inspect", "--format", "{{json .Config}}", &opt.docker_image])
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    let config: serde_json::Value = match output {
+        Some(output) => serde_json::from_slice(&output.stdout).unwrap_or_default(),
+        None => {
+            eprintln!("Can't inspect image {}", opt.docker_image);
+            process::exit(exit::TOOLCHAIN);
+        }
+    };
 
-    let success = Command::new("docker")
-        .args(&["volume", "inspect", &name])
-        .status_bool();
+    let strings = |key: &str| -> Vec<String> {
+        config[key]
+            .as_array()
+            .map(|values| values.iter().filter_map(|v| v.as_str()).map(str::to_owned).collect())
+            .unwrap_or_default()
+    };
+    let mut command = strings("Entrypoint");
+    command.extend(strings("Cmd"));
+    command
+}
 
-    if !success {
-        println!("Didn't find build volume {}, creating it", name);
+/// Exits when an --exclude isn't a directory within the project
+pub(crate) fn check_excludes(opt: &Opt) {
+    for dir in &opt.exclude {
+        if let Err(e) = exclude_dir(dir) {
+            eprintln!("Invalid --exclude {:?}: {}", dir, e);
+            process::exit(exit::BAD_ARGS);
+        }
+    }
+}
+
+/// The --exclude directory relative to /code with forward slashes, e.g. `target` for `./target/`
+fn exclude_dir(raw: &str) -> Result<String, String> {
+    let dir = raw.replace('\\', "/");
+    if dir.starts_with('/') || dir.chars().nth(1) == Some(':') {
+        return Err("it must be relative to the project directory".to_owned());
+    }
+    let parts: Vec<&str> = dir.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+    if parts.contains(&"..") {
+        return Err("it must be within the project directory".to_owned());
+    }
+    if parts.is_empty() {
+        return Err("it would hide the whole project".to_owned());
+    }
+    Ok(parts.join("/"))
+}
+
+/// Warns when a huge target directory is about to be mounted read-write into the container
+pub(crate) fn warn_large_target(project_dir: &Path, opt: &Opt) {
+    const LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+    let target_dir = util::target_dir(project_dir);
+    if target_dir == project_dir.join("target")
+        && opt.exclude.iter().any(|dir| exclude_dir(dir).is_ok_and(|dir| dir == "target"))
+    {
+        return;
+    }
+    if dir_size_exceeds(&target_dir, LIMIT, &mut 0) {
+        println!(
+            "Warning: target/ is over {} GB and mounted read-write into the container, \
+             consider --exclude target",
+            LIMIT / 1024 / 1024 / 1024
+        );
+    }
+}
+
+/// Warns when the host partition of the target directory or the docker disk has less than
+/// --min-free-space GB free, refusing to build with --strict-disk
+pub(crate) fn check_disk_space(project_dir: &Path, opt: &Opt) {
+    let docker_free = docker_free(opt);
+    let target_dir = util::target_dir(project_dir);
+    let host_free = host_free(&target_dir);
+    if opt.verbose {
+        println!("Free space on the docker disk: {}", gigabytes(docker_free));
+        println!("Free space for {}: {}", target_dir.display(), gigabytes(host_free));
+        let usage = Command::new(&opt.container_runtime)
+            .args(["system", "df", "--format", "{{.Type}}: {{.Size}} ({{.Reclaimable}} reclaimable)"])
+            .output();
+        if let Ok(usage) = usage {
+            print!("{}", String::from_utf8_lossy(&usage.stdout));
+        }
+    }
+
+    if warn_low_disk_space(docker_free, host_free, opt) && opt.strict_disk {
+        eprintln!("Less than {} GB free, not building (--strict-disk)", opt.min_free_space);
+        process::exit(1);
+    }
+}
+
+/// Free bytes on the docker disk: with a local daemon on linux of its data directory, otherwise
+/// Docker Desktop's VM disk size less what `docker system df` reports used. Without those figures
+/// --strict-disk looks from a container.
+fn docker_free(opt: &Opt) -> Option<u64> {
+    let info = Command::new(&opt.container_runtime)
+        .args(["info", "--format", "{{.OperatingSystem}}\n{{.DockerRootDir}}"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    let mut info = info.lines();
+    let desktop = info.next().is_some_and(|os| os.contains("Docker Desktop"));
+    if cfg!(target_os = "linux") && !desktop && !is_remote_daemon() {
+        return info.next().and_then(|root_dir| host_free(Path::new(root_dir.trim())));
+    }
+
+    let used = Command::new(&opt.container_runtime)
+        .args(["system", "df", "--format", "{{.Size}}"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().filter_map(parse_size).sum());
+    match (desktop_disk_size(), used) {
+        (Some(size), Some(used)) => Some(size.saturating_sub(used)),
+        _ if opt.strict_disk => container_free(opt),
+        _ => None,
+    }
+}
+
+/// The size of Docker Desktop's VM disk from its settings, in bytes
+fn desktop_disk_size() -> Option<u64> {
+    let home = PathBuf::from(std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?);
+    let dirs = [
+        home.join("Library").join("Group Containers").join("group.com.docker"),
+        home.join(".docker").join("desktop"),
+        std::env::var_os("APPDATA").map(|appdata| PathBuf::from(appdata).join("Docker")).unwrap_or_default(),
+    ];
+    dirs.iter()
+        .flat_map(|dir| vec![dir.join("settings-store.json"), dir.join("settings.json")])
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|settings| desktop_disk_size_setting(&settings))
+}
+
+/// `DiskSizeMiB` (`diskSizeMiB` in older versions) of Docker Desktop's settings, in bytes
+fn desktop_disk_size_setting(settings: &str) -> Option<u64> {
+    let settings: serde_json::Value = serde_json::from_str(settings).ok()?;
+    let mib = settings.get("DiskSizeMiB").or_else(|| settings.get("diskSizeMiB"))?.as_u64()?;
+    Some(mib * 1024 * 1024)
+}
+
+/// Bytes of a size `docker system df` prints, e.g. `1.2GB` or `512kB`
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let unit_at = size.find(|c: char| c.is_ascii_alphabetic())?;
+    let number: f64 = size[..unit_at].parse().ok()?;
+    let multiplier = match size[unit_at..].to_ascii_uppercase().as_str() {
+        "B" => 1.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        _ => return None,
+    };
+    Some((number * multiplier) as u64)
+}
+
+/// Free bytes on the docker disk seen from a container, whose root lives there
+fn container_free(opt: &Opt) -> Option<u64> {
+    Command::new(&opt.container_runtime)
+        .args(["run", "--rm", "--entrypoint", "df", &opt.docker_image, "-Pk", "/"])
+        .output()
+        .ok()
+        .and_then(|output| df_available(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Checks the free space on the docker disk from inside a container after a failed build, since a
+/// full disk shows up as random I/O errors
+pub(crate) fn check_disk_space_after_failure(project_dir: &Path, opt: &Opt) {
+    warn_low_disk_space(container_free(opt), host_free(&util::target_dir(project_dir)), opt);
+}
+
+/// Free bytes on the partition of `path` or its closest existing ancestor
+fn host_free(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|dir| dir.exists())?;
+    Command::new("df")
+        .arg("-Pk")
+        .arg(existing)
+        .output()
+        .ok()
+        .and_then(|output| df_available(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn gigabytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{:.1} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0)),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Prints how to reclaim space when either disk has less than --min-free-space GB free, returning
+/// whether it did
+fn warn_low_disk_space(docker_free: Option<u64>, host_free: Option<u64>, opt: &Opt) -> bool {
+    let threshold = (opt.min_free_space * 1024.0 * 1024.0 * 1024.0) as u64;
+    if !docker_free.into_iter().chain(host_free).any(|free| free < threshold) {
+        return false;
+    }
+    eprintln!("\n===== Low disk space =====");
+    eprintln!("Free on the docker disk:   {}", gigabytes(docker_free));
+    eprintln!("Free for the target dir:   {}", gigabytes(host_free));
+    eprintln!("Builds fail with random I/O errors when the disk fills up, reclaim space with:");
+    eprintln!("  {} system prune", opt.container_runtime);
+    eprintln!("  {} volume prune", opt.container_runtime);
+    eprintln!("  cargo aws-lambda gc");
+    eprintln!("  cargo clean");
+    true
+}
+
+/// Available bytes from the last line of `df -Pk`
+fn df_available(output: &str) -> Option<u64> {
+    let line = output.lines().last()?;
+    let kilobytes: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Sums file sizes under `dir`, stopping as soon as `limit` is exceeded
+fn dir_size_exceeds(dir: &Path, limit: u64, total: &mut u64) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            if dir_size_exceeds(&entry.path(), limit, total) {
+                return true;
+            }
+        } else {
+            *total += metadata.len();
+            if *total > limit {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Whether root-owned build output should be given back to the host user (docker on linux runs
+/// containers as root against the host filesystem, the docker VMs on macOS and windows don't)
+pub(crate) fn should_map_user(opt: &Opt) -> bool {
+    match opt.user_mapping.as_str() {
+        "on" => true,
+        "off" => false,
+        _ => std::env::consts::OS == "linux",
+    }
+}
+
+/// Chowns the files written by the build container to the host user with a short extra container run
+pub(crate) fn restore_ownership(project_dir: &Path, cargo_home: Option<&Path>, opt: &Opt) {
+    let id = |flag: &str| {
+        Command::new("id")
+            .arg(flag)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let owner = match (id("-u"), id("-g")) {
+        (Some(uid), Some(gid)) => format!("{}:{}", uid, gid),
+        _ => {
+            eprintln!("Warning: can't resolve host uid and gid, build output may be owned by root");
+            return;
+        }
+    };
+
+    let mut args: Vec<String> = vec!["run".into(), "--rm".into()];
+    args.extend(bind_mount(&util::target_dir(project_dir), "/code/target", opt));
+    let mut paths = vec!["/code/target".to_owned()];
+    if let (false, Some(cargo_home)) = (opt.use_build_volume, cargo_home) {
+        for dir in &["registry", "git"] {
+            args.extend(bind_mount(&cargo_home.join(dir), &format!("/root/.cargo/{}", dir), opt));
+            paths.push(format!("/root/.cargo/{}", dir));
+        }
+    }
+    if opt.sccache {
+        args.extend(bind_mount(&sccache_dir(opt), "/sccache", opt));
+        paths.push("/sccache".to_owned());
+    }
+    args.extend(vec!["--entrypoint".into(), "chown".into(), opt.docker_image.clone()]);
+    args.push("-R".into());
+    args.push(owner);
+    args.extend(paths);
+
+    if !Command::new(&opt.container_runtime).args(&args).status_bool() {
+        eprintln!("Warning: failed to chown build output, it may be owned by root");
+    }
+}
+
+/// Whether DOCKER_HOST points to a daemon that can't see the local filesystem
+pub fn is_remote_daemon() -> bool {
+    match std::env::var("DOCKER_HOST") {
+        Ok(host) => {
+            let host = host.trim();
+            !(host.is_empty()
+                || host.starts_with("unix://")
+                || host.starts_with("npipe://")
+                || host.contains("://localhost")
+                || host.contains("://127.0.0.1"))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Builds by copying the project and cargo caches into a container and the zip back out, failing
+/// with the exit code
+pub(crate) fn build_with_copy(
+    project_dir: &Path,
+    cargo_home: Option<&Path>,
+    container: &str,
+    zip_paths: &[PathBuf],
+    target: Option<&str>,
+    opt: &Opt,
+) -> Result<(), i32> {
+    let runtime = &opt.container_runtime;
+    let args = create_args(container, target, opt);
+
+    println!("Running {} with args {}", runtime, args.join(" "));
+
+    let output = Command::new(runtime).args(&args).envs(bin_env(opt)).output();
+    match output {
+        Ok(ref output) if output.status.success() => {}
+        e => {
+            eprintln!("Failed to create build container: {:?}", e);
+            return Err(exit::TOOLCHAIN);
+        }
+    }
+
+    let target_dir = util::target_dir(project_dir);
+    let mut zips_in_container = Vec::new();
+    for zip_path in zip_paths {
+        match zip_path.strip_prefix(&target_dir) {
+            Ok(path) => zips_in_container.push(format!("/code/target/{}", unix_path(path))),
+            Err(_) => {
+                eprintln!(
+                    "Building on a remote docker daemon needs the zip inside the target directory, \
+                     not {}",
+                    zip_path.display()
+                );
+                process::exit(exit::BAD_ARGS);
+            }
+        }
+        if let Some(dir) = zip_path.parent() {
+            fs::create_dir_all(dir).expect("Can't create zip directory");
+        }
+    }
+    let cargo_dirs = match (opt.use_build_volume, cargo_home) {
+        (false, Some(cargo_home)) => vec![cargo_home.join("registry"), cargo_home.join("git")],
+        _ => Vec::new(),
+    };
+    let copied_in = copy_into(runtime, project_dir, container, "/code")
+        && cargo_dirs.iter().filter(|dir| dir.exists()).all(|dir| {
+            let to = format!("/root/.cargo/{}", dir.file_name().unwrap().to_string_lossy());
+            copy_into(runtime, dir, container, &to)
+        });
+    let result = if !copied_in {
+        Err(exit::TOOLCHAIN)
+    } else {
+        run_container(
+            Command::new(runtime).args(["start", "--attach", container]),
+            container,
+            project_dir,
+            opt,
+        )
+        .and_then(|()| {
+            let copied_out = zip_paths.iter().zip(&zips_in_container).all(|(zip_path, zip_in_container)| {
+                Command::new(runtime)
+                    .arg("cp")
+                    .arg(format!("{}:{}", container, zip_in_container))
+                    .arg(zip_path)
+                    .status_bool()
+            });
+            if copied_out { Ok(()) } else { Err(exit::TOOLCHAIN) }
+        })
+    };
+
+    if !opt.keep_container {
+        Command::new(runtime)
+            .args(["rm", "--force", container])
+            .output()
+            .ok();
+    }
+    result
+}
+
+/// Unique name for the build container, so it can be killed on timeout or Ctrl+C and found
+/// with --keep-container
+pub(crate) fn container_name(opt: &Opt) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    format!("{}-{}-{}-{}", LABEL, opt.bin.join("-"), project_hash(opt), timestamp)
+}
+
+/// `BIN` for the build image, which packages only that binary, unset when building several
+pub(crate) fn bin_env(opt: &Opt) -> Vec<(&'static str, &str)> {
+    match opt.bin.as_slice() {
+        [bin] => vec![("BIN", bin.as_str())],
+        _ => Vec::new(),
+    }
+}
+
+/// The build command for --print-docker-command as one shell-quoted line, with the variables the
+/// runtime's process gets from `bin_env` in front
+pub(crate) fn shell_command(args: &[String], opt: &Opt) -> String {
+    let env = bin_env(opt)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, shell_quote(value)));
+    let command = std::iter::once(shell_quote(&opt.container_runtime))
+        .chain(args.iter().map(|arg| shell_quote(arg)));
+    env.chain(command).collect::<Vec<_>>().join(" ")
+}
+
+/// Quotes `arg` for POSIX shells when it has anything but safe characters
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_owned()
     } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Prints how to get into a build container kept with --keep-container
+pub(crate) fn print_debug_hints(container: &str, opt: &Opt) {
+    let runtime = &opt.container_runtime;
+    eprintln!("The build container {} was kept for debugging, get into it with", container);
+    eprintln!("    {} start -ai {}", runtime, container);
+    eprintln!("or while it's running with");
+    eprintln!("    {} exec -it {} bash", runtime, container);
+    eprintln!("and remove it afterwards with {} rm {}", runtime, container);
+}
+
+/// IDs of stopped containers created by us
+fn stopped_containers(runtime: &str) -> Vec<String> {
+    Command::new(runtime)
+        .args([
+            "ps",
+            "--all",
+            "--quiet",
+            "--filter",
+            &format!("label={}", LABEL),
+            "--filter",
+            "status=exited",
+        ])
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Warns when kept build containers pile up
+pub(crate) fn warn_stopped_containers(opt: &Opt) {
+    let count = stopped_containers(&opt.container_runtime).len();
+    if count >= 5 {
+        println!(
+            "Warning: there are {} stopped build containers, remove them with --gc-containers",
+            count
+        );
+    }
+}
+
+/// Removes stopped containers created by us
+pub(crate) fn gc_containers(opt: &Opt) {
+    let containers = stopped_containers(&opt.container_runtime);
+    if containers.is_empty() {
+        println!("No stopped build containers to remove");
         return;
     }
 
-    let success = Command::new("docker")
-        .args(&["volume", "create", &name])
+    let success = Command::new(&opt.container_runtime)
+        .arg("rm")
+        .args(&containers)
         .status_bool();
-
     if !success {
-        eprintln!("Failed to create docker build volume {}", name);
-        ::std::process::exit(1);
+        eprintln!("Failed to remove stopped build containers");
+        process::exit(exit::TOOLCHAIN);
+    }
+    println!("Removed {} stopped build containers", containers.len());
+}
+
+/// Runs an attached container command, killing the container when --build-timeout expires or on
+/// Ctrl+C. Transient docker failures (not compiler errors) are retried up to --docker-retries times,
+/// and fail with the toolchain exit code when they persist.
+pub(crate) fn run_container(
+    cmd: &mut Command,
+    container: &str,
+    project_dir: &Path,
+    opt: &Opt,
+) -> Result<(), i32> {
+    let attempts = opt.docker_retries + 1;
+    let mut code = 1;
+    for attempt in 1..=attempts {
+        let (success, stderr) = attach_container(cmd, container, project_dir, opt);
+        if success {
+            return Ok(());
+        }
+        let transient = transient_error(&stderr);
+        // A docker failure rather than the build's own
+        code = if transient.is_some() { exit::TOOLCHAIN } else { 1 };
+        if attempt == attempts {
+            break;
+        }
+        match transient {
+            Some(error) => {
+                let backoff = Duration::from_secs(5 * u64::from(attempt));
+                println!(
+                    "Transient {} failure ({}), retrying in {} s (attempt {} of {})",
+                    opt.container_runtime,
+                    error,
+                    backoff.as_secs(),
+                    attempt + 1,
+                    attempts
+                );
+                thread::sleep(backoff);
+                // A container left behind by a failed `run` would clash with the name, while
+                // `start` reuses the container
+                if cmd.get_args().next() == Some(OsStr::new("run")) {
+                    Command::new(&opt.container_runtime)
+                        .args(["rm", "--force", container])
+                        .output()
+                        .ok();
+                }
+            }
+            None => {
+                println!("Not retrying, the failure doesn't look transient");
+                break;
+            }
+        }
+    }
+    Err(code)
+}
+
+/// The transient error in the stderr of a failed docker run, None for compiler errors and
+/// anything unknown
+fn transient_error(stderr: &[String]) -> Option<&'static str> {
+    let compile_error = stderr
+        .iter()
+        .any(|line| COMPILER_ERRORS.iter().any(|error| line.contains(error)));
+    if compile_error {
+        return None;
+    }
+    TRANSIENT_ERRORS
+        .iter()
+        .find(|error| stderr.iter().any(|line| line.contains(*error)))
+        .copied()
+}
+
+/// One attempt of `run_container`, returning whether it succeeded and the lines it wrote to stderr
+fn attach_container(
+    cmd: &mut Command,
+    container: &str,
+    project_dir: &Path,
+    opt: &Opt,
+) -> (bool, Vec<String>) {
+    CTRLC_HANDLER.call_once(|| {
+        let result = ctrlc::set_handler(|| {
+            if let Some((runtime, container)) = RUNNING_CONTAINER.lock().unwrap().take() {
+                eprintln!("\nInterrupted, killing build container {}", container);
+                kill_container(&runtime, &container);
+            }
+            process::exit(130);
+        });
+        if let Err(e) = result {
+            eprintln!("Warning: can't handle Ctrl+C, the build container may be left running: {}", e);
+        }
+    });
+    *RUNNING_CONTAINER.lock().unwrap() = Some((opt.container_runtime.clone(), container.to_owned()));
+
+    // Stderr is read in both modes, the retries need it to tell transient failures apart
+    if !opt.raw_build_output {
+        cmd.stdout(Stdio::piped());
+    }
+    cmd.stderr(Stdio::piped());
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to run {}: {}", opt.container_runtime, e);
+            RUNNING_CONTAINER.lock().unwrap().take();
+            process::exit(exit::TOOLCHAIN);
+        }
+    };
+
+    let output_handles = if opt.raw_build_output {
+        progress::tee_stderr(&mut child)
     } else {
-        println!("Created docker volume {}", name)
+        progress::forward(&mut child, Some(project_dir), util::color_enabled(opt))
+    };
+
+    let deadline = opt.build_timeout.map(|timeout| Instant::now() + timeout);
+    let success = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                // SIGKILL, which is what the kernel's OOM killer sends
+                if status.code() == Some(137) {
+                    eprintln!(
+                        "The build container was killed (exit code 137), likely for running out of \
+                         memory. Raise the limit with --build-memory (or Docker Desktop's memory \
+                         setting), or lower codegen-units or turn off LTO in the release profile."
+                    );
+                }
+                break status.success();
+            }
+            Ok(None) => {}
+            Err(_) => break false,
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            RUNNING_CONTAINER.lock().unwrap().take();
+            eprintln!(
+                "Build timed out after {} s, killing build container {}",
+                opt.build_timeout.unwrap().as_secs(),
+                container
+            );
+            kill_container(&opt.container_runtime, container);
+            child.kill().ok();
+            child.wait().ok();
+            process::exit(exit::TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+
+    RUNNING_CONTAINER.lock().unwrap().take();
+    let stderr = output_handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap_or_default())
+        .collect();
+    (success, stderr)
+}
+
+fn kill_container(runtime: &str, container: &str) {
+    Command::new(runtime)
+        .args(["kill", container])
+        .output()
+        .ok();
+}
+
+fn copy_into(runtime: &str, from: &Path, container: &str, to: &str) -> bool {
+    println!("Copying {} to build container", from.display());
+    Command::new(runtime)
+        .arg("cp")
+        .arg(from.join("."))
+        .arg(format!("{}:{}", container, to))
+        .status_bool()
+}
+
+fn unix_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(crate) fn manage_build_volume(opt: &Opt) {
+    let runtime = &opt.container_runtime;
+    let labeled = list_volumes(runtime, Some(LABEL));
+    let existing = list_volumes(runtime, None);
+    let project_label = format!("{}.project={}", LABEL, project_dir(opt).display());
+
+    let legacy = legacy_build_volume_name(opt);
+    if opt.build_volume_name.is_none() && existing.lines().any(|line| line.trim() == legacy) {
+        println!(
+            "Found build volume {} from an older version, it may be shared with other projects named the same",
+            legacy
+        );
+        if io::stdin().is_terminal() && util::confirm(&format!("Remove {}?", legacy)) {
+            if !Command::new(runtime).args(["volume", "rm", &legacy]).status_bool() {
+                eprintln!("Failed to remove {} build volume {}", runtime, legacy);
+            }
+        } else {
+            println!("Remove it with `{} volume rm {}` when it's no longer used", runtime, legacy);
+        }
+    }
+
+    let volumes = build_volumes(opt);
+    if volumes[..2].iter().all(|name| !existing.lines().any(|line| line.trim() == name.as_str()))
+        && existing.lines().any(|line| line.trim() == build_volume_name(opt))
+    {
+        println!(
+            "The build volume {} was used both for build artifacts and the cargo registry, which \
+             are now split into volumes of their own. Remove it with `{} volume rm {}`.",
+            build_volume_name(opt),
+            runtime,
+            build_volume_name(opt)
+        );
+    }
+
+    for name in &volumes {
+        if labeled.lines().any(|line| line.trim() == name.as_str()) {
+            continue;
+        }
+        if existing.lines().any(|line| line.trim() == name.as_str()) {
+            println!(
+                "Using unlabeled build volume {}, recreate it with --recreate-build-volume for gc support",
+                name
+            );
+            continue;
+        }
+        println!("Didn't find build volume {}, creating it", name);
+
+        let success = Command::new(runtime)
+            .args(["volume", "create", "--label", &format!("{}=1", LABEL), "--label", &project_label])
+            .arg(name)
+            .status_bool();
+
+        if !success {
+            eprintln!("Failed to create {} build volume {}", runtime, name);
+            ::std::process::exit(exit::TOOLCHAIN);
+        } else {
+            println!("Created {} volume {}", runtime, name)
+        }
     }
 }
 
-pub fn check() {
-    let result = Command::new("docker").args(&["--version"]).output();
-    match result {
-        Ok(ref output) if output.status.success() => {}
+/// Fails early when --sccache is given but the image has no sccache to wrap rustc with
+pub(crate) fn check_sccache(opt: &Opt) {
+    let found = Command::new(&opt.container_runtime)
+        .args(["run", "--rm", "--entrypoint", "sccache", &opt.docker_image, "--version"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !found {
+        eprintln!(
+            "--sccache needs sccache in the build image but {} doesn't have it, \
+             use an image with sccache installed (e.g. one built FROM it with `cargo install sccache`)",
+            opt.docker_image
+        );
+        process::exit(exit::TOOLCHAIN);
+    }
+}
+
+/// Pinning the toolchain needs rustup in the build image
+pub(crate) fn check_rustup(opt: &Opt) {
+    let found = Command::new(&opt.container_runtime)
+        .args(["run", "--rm", "--entrypoint", "rustup", &opt.docker_image, "--version"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !found {
+        eprintln!(
+            "The toolchain is pinned (with --toolchain or rust-toolchain.toml) but {} has no rustup \
+             to install it, use an image with rustup or remove the pin",
+            opt.docker_image
+        );
+        process::exit(exit::TOOLCHAIN);
+    }
+}
+
+/// Host directory for the sccache cache, --sccache-dir or ~/.cache/cargo-aws-lambda/sccache
+fn sccache_dir(opt: &Opt) -> PathBuf {
+    match opt.sccache_dir {
+        Some(ref dir) => dir.clone(),
+        None => {
+            let home = std::env::var_os("HOME").expect("Missing HOME");
+            let mut dir = PathBuf::from(home);
+            dir.extend(&[".cache", "cargo-aws-lambda", "sccache"]);
+            dir
+        }
+    }
+}
+
+/// Strips `dir`/bootstrap with the build image's strip, for hosts without one that handles it
+pub(crate) fn strip(dir: &Path, opt: &Opt) -> bool {
+    let mut args: Vec<String> = vec!["run".into(), "--rm".into()];
+    let host = Host::probe(opt);
+    if host.supports_platform {
+        args.push("--platform".into());
+        args.push("linux/amd64".into());
+    }
+    args.extend(host.mount(dir, "/strip"));
+    args.extend(vec![
+        "--entrypoint".into(),
+        "strip".into(),
+        opt.docker_image.clone(),
+        "--strip-all".into(),
+        "/strip/bootstrap".into(),
+    ]);
+    Command::new(&opt.container_runtime)
+        .args(args)
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Builds the --dockerfile image with the current directory as the context, tagged with a hash of
+/// the Dockerfile so unchanged ones hit the cache, and returns the tag
+pub(crate) fn build_image(dockerfile: &Path, opt: &Opt) -> String {
+    let contents = fs::read(dockerfile).unwrap_or_else(|e| {
+        eprintln!("Can't read Dockerfile {}: {}", dockerfile.display(), e);
+        process::exit(exit::BAD_ARGS);
+    });
+    let tag = format!("cargo-aws-lambda-local:{:016x}", util::fnv1a(&contents));
+
+    println!("Building image {} from {}", tag, dockerfile.display());
+    let success = Command::new(&opt.container_runtime)
+        .arg("build")
+        .arg("-f")
+        .arg(dockerfile)
+        .args(["-t", &tag, "."])
+        .status_bool();
+    if !success {
+        eprintln!("Building image from {} failed, check the output above", dockerfile.display());
+        process::exit(exit::TOOLCHAIN);
+    }
+    println!("Using image {}", tag);
+    tag
+}
+
+/// Pulls the image according to --pull, checks that a digest pinned image (`image@sha256:...`) is
+/// the one available locally and prints the digest of the image used
+pub(crate) fn prepare_image(opt: &Opt) {
+    let runtime = &opt.container_runtime;
+    let image = &opt.docker_image;
+
+    let available = image_digests(runtime, image).is_some();
+    let pull = match opt.pull.as_str() {
+        "always" => true,
+        "never" => false,
+        _ => !available,
+    };
+    if pull {
+        println!("Pulling image {}", image);
+        if !Command::new(runtime).args(["pull", image]).status_bool() {
+            eprintln!("Failed to pull image {}", image);
+            process::exit(exit::TOOLCHAIN);
+        }
+    } else if !available {
+        eprintln!("Image {} not available locally and --pull is never", image);
+        process::exit(exit::TOOLCHAIN);
+    }
+
+    let digests = image_digests(runtime, image).unwrap_or_default();
+    let expected = match image.find('@') {
+        Some(i) => &image[i + 1..],
+        None => {
+            println!("Using image {} ({})", image, digests.join(", "));
+            return;
+        }
+    };
+    let suffix = format!("@{}", expected);
+    match digests.iter().find(|digest| digest.ends_with(&suffix)) {
+        Some(digest) => println!("Using image {}", digest),
+        None => {
+            eprintln!(
+                "Image {} doesn't match the requested digest {}, found {:?}",
+                image, expected, digests
+            );
+            process::exit(exit::TOOLCHAIN);
+        }
+    }
+}
+
+/// Id of the locally available image, which also identifies images built locally
+pub(crate) fn image_id(opt: &Opt) -> Option<String> {
+    let output = Command::new(&opt.container_runtime)
+        .args(["image", "inspect", "--format", "{{.Id}}", &opt.docker_image])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Repo digests of a locally available image, None when the image isn't available
+fn image_digests(runtime: &str, image: &str) -> Option<Vec<String>> {
+    let output = Command::new(runtime)
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{range .RepoDigests}}{{println .}}{{end}}",
+            image,
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let digests = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect();
+    Some(digests)
+}
+
+/// Removes the project's build volumes, if they exist
+pub(crate) fn remove_build_volume(opt: &Opt) {
+    let runtime = &opt.container_runtime;
+    let existing = list_volumes(runtime, None);
+    let mut volumes = build_volumes(opt).to_vec();
+    // The volume that was used for both artifacts and the registry before they were split
+    volumes.push(build_volume_name(opt));
+
+    for name in &volumes {
+        if !existing.lines().any(|line| line.trim() == name.as_str()) {
+            println!("Build volume {} doesn't exist", name);
+            continue;
+        }
+
+        let success = Command::new(runtime)
+            .args(["volume", "rm", name.as_str()])
+            .status_bool();
+
+        if !success {
+            eprintln!("Failed to remove {} build volume {}", runtime, name);
+            ::std::process::exit(exit::TOOLCHAIN);
+        } else {
+            println!("Removed {} volume {}", runtime, name)
+        }
+    }
+}
+
+/// Names of all volumes or the ones with the given label, one per line
+fn list_volumes(runtime: &str, label: Option<&str>) -> String {
+    // podman and docker disagree on `volume inspect` exit codes, so look the volumes up by name instead
+    let mut cmd = Command::new(runtime);
+    cmd.args(["volume", "ls", "--quiet"]);
+    if let Some(label) = label {
+        cmd.arg("--filter").arg(format!("label={}", label));
+    }
+    let volumes = cmd.output();
+    match volumes {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
         e => {
+            eprintln!("Failed to list {} volumes: {:?}", runtime, e);
+            ::std::process::exit(exit::TOOLCHAIN);
+        }
+    }
+}
+
+/// Lists our labeled build volumes with their sizes and removes the ones whose project is gone
+pub fn gc_volumes(runtime: &str, yes: bool) {
+    let output = Command::new(runtime)
+        .args([
+            "volume",
+            "ls",
+            "--filter",
+            &format!("label={}", LABEL),
+            "--format",
+            &format!("{{{{.Name}}}}\t{{{{.Label \"{}.project\"}}}}", LABEL),
+        ])
+        .output();
+    let volumes = match output {
+        Ok(ref output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, '\t');
+                Some((parts.next()?.trim().to_owned(), parts.next().unwrap_or("").trim().to_owned()))
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect::<Vec<_>>(),
+        e => {
+            eprintln!("Failed to list {} volumes: {:?}", runtime, e);
+            process::exit(exit::TOOLCHAIN);
+        }
+    };
+
+    if volumes.is_empty() {
+        println!("No build volumes found");
+        return;
+    }
+
+    let sizes = volume_sizes(runtime);
+    let mut orphaned = Vec::new();
+    for (name, project) in &volumes {
+        let missing = project.is_empty() || !Path::new(project).exists();
+        println!(
+            "{:<50} {:>10}  {}{}",
+            name,
+            sizes.get(name).map(String::as_str).unwrap_or("?"),
+            project,
+            if missing { " (project missing)" } else { "" }
+        );
+        if missing {
+            orphaned.push(name.clone());
+        }
+    }
+
+    if orphaned.is_empty() {
+        println!("No build volumes of missing projects to remove");
+        return;
+    }
+    if !yes && !util::confirm(&format!("Remove {} build volumes of missing projects?", orphaned.len())) {
+        return;
+    }
+
+    let success = Command::new(runtime)
+        .args(["volume", "rm"])
+        .args(&orphaned)
+        .status_bool();
+    if !success {
+        eprintln!("Failed to remove build volumes");
+        process::exit(exit::TOOLCHAIN);
+    }
+    println!("Removed {} build volumes", orphaned.len());
+}
+
+/// Volume sizes parsed from the volumes section of `docker system df -v`
+fn volume_sizes(runtime: &str) -> HashMap<String, String> {
+    let output = match Command::new(runtime).args(["system", "df", "-v"]).output() {
+        Ok(output) => output,
+        Err(_) => return HashMap::new(),
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut sizes = HashMap::new();
+    let mut in_volumes = false;
+    for line in stdout.lines() {
+        if line.starts_with("VOLUME NAME") {
+            in_volumes = true;
+            continue;
+        }
+        if in_volumes {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            if columns.len() < 3 {
+                break;
+            }
+            sizes.insert(columns[0].to_owned(), columns[2].to_owned());
+        }
+    }
+    sizes
+}
+
+/// Checks that the runtime is installed and its daemon answers, `--version` alone succeeds with
+/// a stopped daemon
+pub fn check(runtime: &str) {
+    let output = match Command::new(runtime).args(["info"]).output() {
+        Ok(output) => output,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{} is not installed (or not in PATH), install it to build the lambda", runtime);
+            process::exit(exit::TOOLCHAIN);
+        }
+        Err(e) => {
+            eprintln!("Executing {} info failed with {}", runtime, e);
+            process::exit(exit::TOOLCHAIN);
+        }
+    };
+    if output.status.success() {
+        return;
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let daemon = std::env::var("DOCKER_HOST").unwrap_or_else(|_| "unix:///var/run/docker.sock".to_owned());
+    if stderr.to_ascii_lowercase().contains("permission denied") {
+        eprintln!("Permission denied on the {} daemon socket {}", runtime, daemon);
+        if std::env::consts::OS == "linux" {
             eprintln!(
-                "Docker missing, executing docker --version failed with {:?}",
-                e
+                "Add yourself to the docker group with `sudo usermod -aG docker $USER` and log in again"
             );
-            process::exit(1);
+        }
+        eprintln!("{}", stderr.trim());
+        process::exit(exit::DAEMON_PERMISSION);
+    }
+    eprintln!(
+        "Can't reach the {} daemon at {}, start Docker Desktop or the {} service",
+        runtime, daemon, runtime
+    );
+    eprintln!("{}", stderr.trim());
+    process::exit(exit::DAEMON_UNREACHABLE);
+}
+
+/// Arguments bind mounting a host directory, with `--mount` where the runtime has it since `-v`
+/// splits on the colons of windows paths and breaks on some characters
+fn bind_mount(path: &Path, target: &str, opt: &Opt) -> [String; 2] {
+    Host::probe(opt).mount(path, target)
+}
+
+fn mount_args(source: &str, target: &str, supports_mount: bool) -> [String; 2] {
+    if !supports_mount {
+        return ["-v".into(), format!("{}:{}", source, target)];
+    }
+    // The spec is a CSV record, so a quoted field keeps commas and spaces in the path intact
+    let source = format!("\"source={}\"", source.replace('"', "\"\""));
+    ["--mount".into(), format!("type=bind,{},target={}", source, target)]
+}
+
+/// Docker Desktop rejects the drive letter colon in `C:\Users\...`, so use `/c/Users/...` for
+/// windows runtimes, which is also where the WSL `/mnt/c/Users/...` goes. Linux runtimes, including
+/// Docker Desktop's WSL integration, take WSL paths as they are.
+fn mount_path(path: &Path, windows: bool) -> String {
+    let raw = path.display().to_string();
+    if !windows {
+        return raw;
+    }
+
+    let raw = raw.trim_start_matches(r"\\?\").replace('\\', "/");
+    let mut chars = raw.chars();
+    if let (Some(drive), Some(':')) = (chars.next(), chars.next()) {
+        if drive.is_ascii_alphabetic() {
+            return format!("/{}{}", drive.to_ascii_lowercase(), chars.as_str());
         }
     }
+    let wsl = raw.strip_prefix("/mnt/").map(|rest| rest.split_at(rest.find('/').unwrap_or(rest.len())));
+    match wsl {
+        Some((drive, rest)) if drive.len() == 1 && drive.as_bytes()[0].is_ascii_alphabetic() => {
+            format!("/{}{}", drive.to_ascii_lowercase(), rest)
+        }
+        _ => raw,
+    }
 }
 
-fn build_volume_name() -> String {
-    let cwd = std::env::current_dir().expect("Can't get cwd");
-    let basename = cwd
+/// Prints a note when the build container is about to be emulated, e.g. amd64 on Apple Silicon
+/// or arm64 with --docker-platform on x86 hosts
+pub fn note_emulation(target: Option<&str>, opt: &Opt) {
+    let image_arch = match (&opt.docker_platform, target) {
+        (Some(platform), _) => platform.split('/').nth(1).unwrap_or("").to_owned(),
+        (None, Some(target)) if target.starts_with("aarch64") => "arm64".to_owned(),
+        (None, _) => "amd64".to_owned(),
+    };
+    // uname sees through Rosetta, which std::env::consts::ARCH doesn't
+    let host_arch = Command::new("uname")
+        .arg("-m")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_else(|_| std::env::consts::ARCH.to_owned());
+    let host_arch = match host_arch.as_str() {
+        "x86_64" => "amd64",
+        "aarch64" | "arm64" => "arm64",
+        _ => return,
+    };
+    if image_arch.is_empty() || image_arch == host_arch {
+        return;
+    }
+
+    if host_arch == "arm64" && std::env::consts::OS == "macos" {
+        println!(
+            "Note: building for x86_64 on Apple Silicon runs under emulation and is slow. \
+             Consider --arch arm64 with an arm64 capable image."
+        );
+    } else {
+        println!(
+            "Note: building a {} container on a {} host runs under emulation (QEMU) and is slow.",
+            image_arch, host_arch
+        );
+    }
+}
+
+/// Native arm64 hosts, Docker Desktop and linux hosts with qemu binfmt handlers can run arm64 images
+fn can_run_arm64() -> bool {
+    std::env::consts::ARCH == "aarch64"
+        || !cfg!(target_os = "linux")
+        || Path::new("/proc/sys/fs/binfmt_misc/qemu-aarch64").exists()
+}
+
+/// Volume name from the project directory basename and a hash of its full path, so same named
+/// projects in different locations don't share a volume
+fn build_volume_name(opt: &Opt) -> String {
+    if let Some(ref name) = opt.build_volume_name {
+        return name.clone();
+    }
+
+    format!("{}-{}", legacy_build_volume_name(opt), project_hash(opt))
+}
+
+/// Volume name used before the project path hash was added, shared by same named projects
+fn legacy_build_volume_name(opt: &Opt) -> String {
+    let project_dir = project_dir(opt);
+    let basename = project_dir
         .file_name()
         .and_then(OsStr::to_str)
         .expect("Can't get basename from cwd");
     format!("rust-build-volume-{}", basename)
-}
\ No newline at end of file
+}
+
+/// Short hash of the canonicalized project path
+fn project_hash(opt: &Opt) -> String {
+    let project_dir = project_dir(opt);
+    let full_path = project_dir.canonicalize().unwrap_or_else(|_| project_dir.clone());
+    let hash = util::fnv1a(full_path.to_string_lossy().as_bytes());
+    format!("{:08x}", hash as u32)
+}
+
+fn project_dir(opt: &Opt) -> PathBuf {
+    match opt.manifest_path {
+        Some(ref manifest_path) => util::project_dir_of(manifest_path),
+        None => std::env::current_dir().expect("Can't get cwd"),
+    }
+}
+
+/// Names of the volumes for build artifacts, the cargo registry and the cargo git checkouts
+fn build_volumes(opt: &Opt) -> [String; 3] {
+    let name = build_volume_name(opt);
+    [
+        format!("{}-target", name),
+        format!("{}-registry", name),
+        format!("{}-git", name),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn mount_path_windows_drive() {
+        assert_eq!(mount_path(Path::new(r"C:\Users\me\proj"), true), "/c/Users/me/proj");
+        assert_eq!(mount_path(Path::new(r"\\?\D:\work\proj"), true), "/d/work/proj");
+    }
+
+    #[test]
+    fn mount_path_wsl() {
+        assert_eq!(mount_path(Path::new("/mnt/c/Users/me/proj"), true), "/c/Users/me/proj");
+        assert_eq!(mount_path(Path::new("/mnt/D"), true), "/d");
+        assert_eq!(mount_path(Path::new("/mnt/data/proj"), true), "/mnt/data/proj");
+        // Docker in the WSL distro resolves its own paths
+        assert_eq!(mount_path(Path::new("/mnt/c/Users/me/proj"), false), "/mnt/c/Users/me/proj");
+    }
+
+    #[test]
+    fn mount_path_unix_untouched() {
+        let path = Path::new("/home/me/my proj/työ");
+        assert_eq!(mount_path(path, false), "/home/me/my proj/työ");
+    }
+
+    #[test]
+    fn mount_args_spaces_and_unicode() {
+        let source = mount_path(Path::new(r"C:\Users\Jürgen Müller\my, proj"), true);
+        assert_eq!(
+            mount_args(&source, "/code", true),
+            ["--mount".to_owned(), r#"type=bind,"source=/c/Users/Jürgen Müller/my, proj",target=/code"#.to_owned()]
+        );
+        assert_eq!(
+            mount_args("/home/me/\"quoted\" 項目", "/code", true),
+            ["--mount".to_owned(), r#"type=bind,"source=/home/me/""quoted"" 項目",target=/code"#.to_owned()]
+        );
+    }
+
+    #[test]
+    fn mount_args_fallback() {
+        assert_eq!(
+            mount_args("/c/Users/me/proj", "/code/target", false),
+            ["-v".to_owned(), "/c/Users/me/proj:/code/target".to_owned()]
+        );
+    }
+
+    #[test]
+    fn exclude_dir_within_project() {
+        assert_eq!(exclude_dir("target").unwrap(), "target");
+        assert_eq!(exclude_dir("./node_modules/").unwrap(), "node_modules");
+        assert_eq!(exclude_dir(r"frontend\dist").unwrap(), "frontend/dist");
+        for dir in &["", ".", "./", "/", "/tmp", r"C:\proj", "../x", "a/../../b"] {
+            assert!(exclude_dir(dir).is_err(), "{:?} was accepted", dir);
+        }
+    }
+
+    #[test]
+    fn docker_disk_sizes() {
+        assert_eq!(parse_size("1.5GB"), Some(1_500_000_000));
+        assert_eq!(parse_size("512kB"), Some(512_000));
+        assert_eq!(parse_size("0B"), Some(0));
+        assert_eq!(parse_size("n/a"), None);
+        assert_eq!(desktop_disk_size_setting(r#"{"DiskSizeMiB": 65536}"#), Some(64 << 30));
+        assert_eq!(desktop_disk_size_setting(r#"{"diskSizeMiB": 1024, "cpus": 4}"#), Some(1 << 30));
+        assert_eq!(desktop_disk_size_setting(r#"{"cpus": 4}"#), None);
+    }
+
+    fn host(windows: bool, supports_mount: bool) -> Host {
+        Host {
+            supports_mount,
+            supports_platform: true,
+            can_run_arm64: true,
+            windows,
+            stdout_terminal: false,
+            stdin_terminal: false,
+            color: false,
+            columns: "120".to_owned(),
+        }
+    }
+
+    #[test]
+    fn build_args_windows_paths() {
+        let args = ["cargo-aws-lambda", "func", "--exclude", "target", "--exclude", r"web\node_modules"];
+        let mut opt = Opt::from_iter(&args);
+        opt.docker_image = DEFAULT_IMAGE.to_owned();
+        let project_dir = Path::new(r"C:\Users\Jürgen Müller\my, proj");
+        let cargo_home = Path::new(r"C:\Users\Jürgen Müller\.cargo");
+        let host = host(true, true);
+        let args = run_args(project_dir, Some(cargo_home), "c1", None, None, &host, &opt);
+        assert_eq!(
+            args,
+            [
+                "run",
+                "--rm",
+                "--name",
+                "c1",
+                "--label",
+                "cargo-aws-lambda=1",
+                "--mount",
+                r#"type=bind,"source=/c/Users/Jürgen Müller/my, proj",target=/code"#,
+                "-v",
+                "/code/target",
+                "--mount",
+                r#"type=bind,"source=/c/Users/Jürgen Müller/my, proj/target/lambda",target=/code/target/lambda"#,
+                "-v",
+                "/code/web/node_modules",
+                "--mount",
+                r#"type=bind,"source=/c/Users/Jürgen Müller/.cargo/registry",target=/root/.cargo/registry"#,
+                "--mount",
+                r#"type=bind,"source=/c/Users/Jürgen Müller/.cargo/git",target=/root/.cargo/git"#,
+                "--platform",
+                "linux/amd64",
+                "-e",
+                "CARGO_TERM_COLOR=never",
+                DEFAULT_IMAGE,
+            ]
+        );
+    }
+
+    #[test]
+    fn build_args_unix_paths() {
+        let mut opt = Opt::from_iter(&[
+            "cargo-aws-lambda",
+            "func",
+            "--docker-arg=--network=host",
+            "--build-memory",
+            "4g",
+            "--env",
+            "GREETING=hyvää päivää",
+            "--docker-cmd",
+            "make",
+            "--docker-cmd",
+            "lambda",
+        ]);
+        opt.docker_image = DEFAULT_IMAGE.to_owned();
+        let project_dir = Path::new("/home/jürgen/my proj");
+        let target = Some("aarch64-unknown-linux-musl");
+        let host = host(false, false);
+        let args = run_args(project_dir, None, "c1", target, docker_cmd(&opt), &host, &opt);
+        assert_eq!(
+            args,
+            [
+                "run",
+                "--rm",
+                "--name",
+                "c1",
+                "--label",
+                "cargo-aws-lambda=1",
+                "-v",
+                "/home/jürgen/my proj:/code",
+                "--platform",
+                "linux/arm64",
+                "--memory",
+                "4g",
+                "-e",
+                "CARGO_TERM_COLOR=never",
+                "-e",
+                "CARGO_FLAGS=--target aarch64-unknown-linux-musl",
+                "-e",
+                "GREETING=hyvää päivää",
+                "--network=host",
+                "--entrypoint",
+                "make",
+                DEFAULT_IMAGE,
+                "lambda",
+            ]
+        );
+    }
+}
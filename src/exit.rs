@@ -0,0 +1,30 @@
+use rusoto_core::RusotoError;
+
+// Process exit codes, documented in README.md. Anything not covered below exits with 1.
+
+/// Docker, cargo or another required tool is missing or misbehaving
+pub const TOOLCHAIN: i32 = 2;
+/// AWS credentials couldn't be loaded or were rejected
+pub const CREDENTIALS: i32 = 3;
+/// An AWS API call failed
+pub const AWS_API: i32 = 4;
+/// Invalid command-line arguments or Lambda.toml contents
+pub const BAD_ARGS: i32 = 5;
+/// The build, --wait or a signing job didn't finish in time
+pub const TIMEOUT: i32 = 6;
+/// The docker daemon isn't running or can't be reached
+pub const DAEMON_UNREACHABLE: i32 = 7;
+/// The docker daemon's socket can't be accessed with the user's permissions
+pub const DAEMON_PERMISSION: i32 = 8;
+/// The --invoke smoke test after deploying returned a function error
+pub const FUNCTION_ERROR: i32 = 9;
+
+pub fn for_rusoto_error<E>(err: &RusotoError<E>) -> i32 {
+    match err {
+        RusotoError::Credentials(_) => CREDENTIALS,
+        RusotoError::Unknown(res) if res.status.as_u16() == 401 || res.status.as_u16() == 403 => {
+            CREDENTIALS
+        }
+        _ => AWS_API,
+    }
+}
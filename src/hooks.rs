@@ -0,0 +1,46 @@
+use std::path::Path;
+use std::process;
+use rusoto_lambda::FunctionConfiguration;
+use crate::util::{self, CommandExt};
+
+/// Runs the `pre_build` hooks of Lambda.toml, exiting when one fails
+pub(crate) fn pre_build(project_dir: &Path, key: &str) {
+    for hook in hooks("pre_build", key) {
+        println!("===== Running pre_build hook {} =====", hook);
+        if !util::shell_command(&hook).current_dir(project_dir).status_bool() {
+            eprintln!("pre_build hook {} failed, not deploying", hook);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the `post_deploy` hooks of Lambda.toml with the deployed function in LAMBDA_FUNCTION,
+/// LAMBDA_VERSION and LAMBDA_SHA, returning false when one fails
+pub(crate) fn post_deploy(project_dir: &Path, key: &str, res: &FunctionConfiguration) -> bool {
+    let mut success = true;
+    for hook in hooks("post_deploy", key) {
+        println!("===== Running post_deploy hook {} =====", hook);
+        let passed = util::shell_command(&hook)
+            .current_dir(project_dir)
+            .env("LAMBDA_FUNCTION", res.function_name.clone().unwrap_or_default())
+            .env("LAMBDA_VERSION", res.version.clone().unwrap_or_default())
+            .env("LAMBDA_SHA", res.code_sha_256.clone().unwrap_or_default())
+            .status_bool();
+        if !passed {
+            eprintln!("post_deploy hook {} failed", hook);
+            success = false;
+        }
+    }
+    success
+}
+
+/// The global hooks followed by the function's ones in table [hooks.<key>]
+fn hooks(name: &str, key: &str) -> Vec<String> {
+    let lambda_toml = util::lambda_toml();
+    let function_hooks = lambda_toml.hooks.get(key);
+    let (hooks, function_hooks) = match name {
+        "pre_build" => (&lambda_toml.pre_build, function_hooks.map(|hooks| &hooks.pre_build)),
+        _ => (&lambda_toml.post_deploy, function_hooks.map(|hooks| &hooks.post_deploy)),
+    };
+    hooks.iter().chain(function_hooks.into_iter().flatten()).cloned().collect()
+}
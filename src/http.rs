@@ -0,0 +1,98 @@
+use futures::Future;
+use hyper::client::HttpConnector;
+use hyper::Uri;
+use hyper_proxy::{Custom, Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
+use rusoto_core::request::{DispatchSignedRequest, HttpDispatchError, HttpResponse};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::HttpClient;
+use std::env;
+use std::process;
+use std::time::Duration;
+use crate::{exit, AwsOpt};
+
+type Connector = ProxyConnector<HttpsConnector<HttpConnector>>;
+
+/// Gives the requests of a dispatcher a timeout unless they have one of their own
+pub(crate) struct TimeoutDispatcher<D> {
+    inner: D,
+    timeout: Duration,
+}
+
+impl<D> DispatchSignedRequest for TimeoutDispatcher<D>
+where
+    D: DispatchSignedRequest,
+    D::Future: Send,
+{
+    type Future = Box<dyn Future<Item = HttpResponse, Error = HttpDispatchError> + Send>;
+
+    fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        let timeout = timeout.unwrap_or(self.timeout);
+        Box::new(self.inner.dispatch(request, Some(timeout)).map_err(move |e| {
+            if e.to_string().contains("timed out") {
+                HttpDispatchError::new(format!(
+                    "AWS request timed out after {} s, raise the limit with --aws-timeout",
+                    timeout.as_secs()
+                ))
+            } else {
+                e
+            }
+        }))
+    }
+}
+
+/// The request dispatcher for the AWS clients with the --aws-timeout, going through the proxy in
+/// HTTPS_PROXY or HTTP_PROXY (or their lowercase variants) except for the hosts in NO_PROXY
+pub(crate) fn dispatcher(opt: &AwsOpt) -> TimeoutDispatcher<HttpClient<Connector>> {
+    TimeoutDispatcher {
+        inner: proxy_client(),
+        timeout: Duration::from_secs(opt.aws_timeout),
+    }
+}
+
+fn proxy_client() -> HttpClient<Connector> {
+    let https = HttpsConnector::new(4).expect("failed to create TLS connector");
+    let mut connector = ProxyConnector::new(https).expect("failed to create proxy connector");
+
+    let no_proxy = env_var("NO_PROXY").unwrap_or_default();
+    let no_proxy: Vec<String> = no_proxy
+        .split(',')
+        .map(|host| host.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect();
+    for (var, scheme) in &[("HTTPS_PROXY", "https"), ("HTTP_PROXY", "http")] {
+        let url = match env_var(var) {
+            Some(url) => url,
+            None => continue,
+        };
+        let uri: Uri = url.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid proxy URL in {}: {}", var, e);
+            process::exit(exit::BAD_ARGS);
+        });
+        let no_proxy = no_proxy.clone();
+        let scheme = *scheme;
+        let intercept = Intercept::Custom(Custom::from(
+            move |target: Option<&str>, host: Option<&str>, _port: Option<u16>| {
+                target == Some(scheme) && !host.is_some_and(|host| bypasses(&no_proxy, host))
+            },
+        ));
+        connector.add_proxy(Proxy::new(intercept, uri));
+    }
+    HttpClient::from_connector(connector)
+}
+
+/// Whether NO_PROXY lets `host` be reached directly, `*` or the host or one of its parent domains
+fn bypasses(no_proxy: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy.iter().any(|entry| {
+        entry == "*" || host == *entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+/// The variable or its lowercase variant, which curl and others read too
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_ascii_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
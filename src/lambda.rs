@@ -1,26 +1,324 @@
-use rusoto_core::{HttpClient, Region};
-use crate::Opt;
-use rusoto_lambda::LambdaClient;
-use rusoto_core::credential::{ChainProvider, ProfileProvider, StaticProvider};
-use std::str::FromStr;
-
-pub(crate) fn create_client(opt: &Opt, region: &str) -> LambdaClient {
-    let dispatcher = HttpClient::new().expect("failed to create request dispatcher");
-    let region = Region::from_str(region).unwrap();
-
-    match (&opt.access_key, &opt.secret_key, &opt.profile) {
-        (Some(access_key), Some(secret_key), _) => {
-            let creds = StaticProvider::new_minimal(access_key.to_owned(), secret_key.to_owned());
-            LambdaClient::new_with(dispatcher, creds, region)
-        },
-        (_, _, Some(profile)) => {
-            let mut creds = ProfileProvider::new().unwrap();
-            creds.set_profile(profile.to_owned());
-            LambdaClient::new_with(dispatcher, creds, region)
-        },
-        _ => {
-            let creds = ChainProvider::new();
-            LambdaClient::new_with(dispatcher, creds, region)
+// rusoto's errors are returned as they are for the callers to map to exit codes
+#![allow(clippy::result_large_err)]
+
+use rusoto_core::RusotoError;
+use crate::{AwsOpt, Opt};
+use crate::aws;
+use rusoto_lambda::{
+    DeadLetterConfig, DeleteFunctionConcurrencyError, DeleteFunctionConcurrencyRequest, Environment,
+    FunctionConfiguration, GetFunctionConfigurationError, GetFunctionConfigurationRequest,
+    InvocationRequest, InvocationResponse, InvokeError, Lambda, LambdaClient, ListFunctionsError,
+    ListFunctionsRequest, PublishVersionError, PublishVersionRequest, PutFunctionConcurrencyError,
+    PutFunctionConcurrencyRequest, TracingConfig, UpdateFunctionConfigurationError,
+    UpdateFunctionConfigurationRequest, VpcConfig,
+};
+use std::collections::HashMap;
+
+/// Lambda runtime identifiers accepted by --runtime
+pub(crate) const RUNTIMES: &[&str] = &[
+    "provided",
+    "provided.al2",
+    "provided.al2023",
+    "nodejs18.x",
+    "nodejs20.x",
+    "nodejs22.x",
+    "python3.9",
+    "python3.10",
+    "python3.11",
+    "python3.12",
+    "python3.13",
+    "java8.al2",
+    "java11",
+    "java17",
+    "java21",
+    "dotnet8",
+    "ruby3.2",
+    "ruby3.3",
+];
+
+pub(crate) fn create_client(opt: &AwsOpt, region: &str) -> LambdaClient {
+    aws::client(opt, region, LambdaClient::new_with_client)
+}
+
+/// Reserves concurrency for the function, returning the limit AWS reports back
+pub(crate) fn put_concurrency(
+    client: &LambdaClient,
+    func_name: &str,
+    limit: i64,
+) -> Result<i64, RusotoError<PutFunctionConcurrencyError>> {
+    let req = PutFunctionConcurrencyRequest {
+        function_name: func_name.to_owned(),
+        reserved_concurrent_executions: limit,
+    };
+    let res = client.put_function_concurrency(req).sync()?;
+    Ok(res.reserved_concurrent_executions.unwrap_or(limit))
+}
+
+/// Removes the reserved concurrency limit from the function
+pub(crate) fn delete_concurrency(
+    client: &LambdaClient,
+    func_name: &str,
+) -> Result<(), RusotoError<DeleteFunctionConcurrencyError>> {
+    let req = DeleteFunctionConcurrencyRequest {
+        function_name: func_name.to_owned(),
+    };
+    client.delete_function_concurrency(req).sync()
+}
+
+/// Provisions concurrency for a published version, returning the provisioning status. This version
+/// of rusoto_lambda predates provisioned concurrency, so the request is signed and sent here.
+pub(crate) fn put_provisioned_concurrency(
+    opt: &AwsOpt,
+    region: &str,
+    func_name: &str,
+    version: &str,
+    executions: i64,
+) -> Result<Option<String>, String> {
+    let path = format!("/2019-09-30/functions/{}/provisioned-concurrency?Qualifier={}", func_name, version);
+    let body = serde_json::json!({ "ProvisionedConcurrentExecutions": executions });
+    let res = provisioned_concurrency_request(opt, region, "PUT", &path, Some(body))?;
+    Ok(res["Status"].as_str().map(str::to_owned))
+}
+
+/// The provisioning status of a version and the reason when it failed
+pub(crate) fn provisioned_concurrency_status(
+    opt: &AwsOpt,
+    region: &str,
+    func_name: &str,
+    version: &str,
+) -> Result<(Option<String>, Option<String>), String> {
+    let path = format!("/2019-09-30/functions/{}/provisioned-concurrency?Qualifier={}", func_name, version);
+    let res = provisioned_concurrency_request(opt, region, "GET", &path, None)?;
+    let field = |name: &str| res[name].as_str().map(str::to_owned);
+    Ok((field("Status"), field("StatusReason")))
+}
+
+fn provisioned_concurrency_request(
+    opt: &AwsOpt,
+    region: &str,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    let (status, res) = aws::send(opt, "lambda", region, method, path, body)?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&res)));
+    }
+    serde_json::from_slice(&res).map_err(|e| format!("Unexpected response: {}", e))
+}
+
+/// Publishes the deployed code as a new version with a description
+pub(crate) fn publish_version(
+    client: &LambdaClient,
+    func_name: &str,
+    code_sha_256: Option<String>,
+    description: &str,
+) -> Result<FunctionConfiguration, RusotoError<PublishVersionError>> {
+    let req = PublishVersionRequest {
+        function_name: func_name.to_owned(),
+        code_sha_256,
+        description: Some(description.to_owned()),
+        ..Default::default()
+    };
+    client.publish_version(req).sync()
+}
+
+/// The function's current configuration
+pub(crate) fn get_configuration(
+    client: &LambdaClient,
+    func_name: &str,
+) -> Result<FunctionConfiguration, RusotoError<GetFunctionConfigurationError>> {
+    let req = GetFunctionConfigurationRequest {
+        function_name: func_name.to_owned(),
+        qualifier: None,
+    };
+    client.get_function_configuration(req).sync()
+}
+
+/// Points the function at a container image with UpdateFunctionCode. This version of rusoto_lambda
+/// predates container images and has no ImageUri, so the request is signed and sent here.
+pub(crate) fn update_function_image(
+    opt: &AwsOpt,
+    region: &str,
+    func_name: &str,
+    image_uri: &str,
+    publish: bool,
+    dry_run: bool,
+) -> Result<FunctionConfiguration, String> {
+    let path = format!("/2015-03-31/functions/{}/code", func_name);
+    let body = serde_json::json!({
+        "ImageUri": image_uri,
+        "Publish": publish,
+        "DryRun": dry_run,
+    });
+    let (status, res) = aws::send(opt, "lambda", region, "PUT", &path, Some(body))?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&res)));
+    }
+    serde_json::from_slice(&res).map_err(|e| format!("Unexpected response: {}", e))
+}
+
+/// Creates or updates the function's URL with the auth type (NONE or AWS_IAM) and CORS settings,
+/// returning the URL. Function URLs are newer than this version of rusoto_lambda too.
+pub(crate) fn put_function_url(
+    opt: &AwsOpt,
+    region: &str,
+    func_name: &str,
+    auth_type: &str,
+    cors: Option<serde_json::Value>,
+) -> Result<String, String> {
+    let path = format!("/2021-10-31/functions/{}/url", func_name);
+    let mut body = serde_json::json!({ "AuthType": auth_type });
+    if let Some(cors) = cors {
+        body["Cors"] = cors;
+    }
+    let (status, _) = aws::send(opt, "lambda", region, "GET", &path, None)?;
+    let method = if status == 404 { "POST" } else { "PUT" };
+    let (status, res) = aws::send(opt, "lambda", region, method, &path, Some(body))?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&res)));
+    }
+    let res: serde_json::Value =
+        serde_json::from_slice(&res).map_err(|e| format!("Unexpected response: {}", e))?;
+    res["FunctionUrl"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| "No FunctionUrl in the response".to_owned())
+}
+
+/// Lets anyone invoke the function through its URL, which auth type NONE needs on top of the URL
+/// itself. An existing permission is left as is.
+pub(crate) fn allow_public_url(opt: &AwsOpt, region: &str, func_name: &str) -> Result<(), String> {
+    let path = format!("/2015-03-31/functions/{}/policy", func_name);
+    let body = serde_json::json!({
+        "StatementId": "FunctionURLAllowPublicAccess",
+        "Action": "lambda:InvokeFunctionUrl",
+        "Principal": "*",
+        "FunctionUrlAuthType": "NONE",
+    });
+    match aws::send(opt, "lambda", region, "POST", &path, Some(body))? {
+        (status, _) if (200..300).contains(&status) || status == 409 => Ok(()),
+        (status, res) => Err(format!("{}: {}", status, String::from_utf8_lossy(&res))),
+    }
+}
+
+/// All functions in the client's region, following the pages
+pub(crate) fn list_functions(
+    client: &LambdaClient,
+) -> Result<Vec<FunctionConfiguration>, RusotoError<ListFunctionsError>> {
+    let mut functions = Vec::new();
+    let mut marker = None;
+    loop {
+        let req = ListFunctionsRequest {
+            marker,
+            ..Default::default()
+        };
+        let res = client.list_functions(req).sync()?;
+        functions.extend(res.functions.unwrap_or_default());
+        marker = res.next_marker;
+        if marker.is_none() {
+            return Ok(functions);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Invokes the function synchronously with `payload`, returning the tail of its log too
+pub(crate) fn invoke(
+    client: &LambdaClient,
+    func_name: &str,
+    qualifier: Option<String>,
+    payload: &str,
+) -> Result<InvocationResponse, RusotoError<InvokeError>> {
+    let req = InvocationRequest {
+        function_name: func_name.to_owned(),
+        qualifier,
+        log_type: Some("Tail".to_owned()),
+        payload: Some(payload.to_owned().into()),
+        ..Default::default()
+    };
+    client.invoke(req).sync()
+}
+
+/// The function's current environment variables
+pub(crate) fn get_environment(
+    client: &LambdaClient,
+    func_name: &str,
+) -> Result<HashMap<String, String>, RusotoError<GetFunctionConfigurationError>> {
+    let config = get_configuration(client, func_name)?;
+    Ok(config
+        .environment
+        .and_then(|environment| environment.variables)
+        .unwrap_or_default())
+}
+
+/// Configuration changes requested on the command line, None when there's nothing to update.
+/// `environment` replaces the function's environment variables.
+pub(crate) fn configuration_update(
+    func_name: &str,
+    opt: &Opt,
+    environment: Option<HashMap<String, String>>,
+) -> Option<UpdateFunctionConfigurationRequest> {
+    let mut req = UpdateFunctionConfigurationRequest {
+        function_name: func_name.to_owned(),
+        ..Default::default()
+    };
+    let mut changed = false;
+
+    if let Some(variables) = environment {
+        req.environment = Some(Environment {
+            variables: Some(variables),
+        });
+        changed = true;
+    }
+
+    if !opt.subnets.is_empty() || !opt.security_groups.is_empty() {
+        req.vpc_config = Some(VpcConfig {
+            subnet_ids: Some(opt.subnets.clone()),
+            security_group_ids: Some(opt.security_groups.clone()),
+        });
+        changed = true;
+    }
+
+    if let Some(ref dlq) = opt.dlq {
+        req.dead_letter_config = Some(DeadLetterConfig {
+            target_arn: Some(dlq.clone()),
+        });
+        changed = true;
+    } else if opt.no_dlq {
+        // An empty target removes the dead-letter queue
+        req.dead_letter_config = Some(DeadLetterConfig {
+            target_arn: Some(String::new()),
+        });
+        changed = true;
+    }
+
+    if let Some(ref mode) = opt.tracing {
+        req.tracing_config = Some(TracingConfig {
+            mode: Some(mode.clone()),
+        });
+        changed = true;
+    }
+
+    if let Some(ref runtime) = opt.runtime {
+        req.runtime = Some(runtime.clone());
+        changed = true;
+    }
+
+    if let Some(ref handler) = opt.handler {
+        req.handler = Some(handler.clone());
+        changed = true;
+    }
+
+    if changed {
+        Some(req)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn update_configuration(
+    client: &LambdaClient,
+    req: UpdateFunctionConfigurationRequest,
+) -> Result<FunctionConfiguration, RusotoError<UpdateFunctionConfigurationError>> {
+    client.update_function_configuration(req).sync()
+}
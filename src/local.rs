@@ -0,0 +1,249 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+use crate::{Builder, Opt};
+use crate::util::{self, CommandExt};
+use crate::exit;
+
+pub(crate) fn check(builder: &Builder) {
+    let tools: &[(&str, &str, &[&str])] = match builder {
+        Builder::Zigbuild => &[
+            ("cargo-zigbuild", "cargo", &["zigbuild", "--version"]),
+            ("zig", "zig", &["version"]),
+        ],
+        _ => &[("cargo", "cargo", &["--version"])],
+    };
+
+    for (name, program, args) in tools {
+        let result = Command::new(program).args(*args).output();
+        match result {
+            Ok(ref output) if output.status.success() => {}
+            e => {
+                eprintln!(
+                    "{} missing, executing {} {} failed with {:?}",
+                    name,
+                    program,
+                    args.join(" "),
+                    e
+                );
+                process::exit(exit::TOOLCHAIN);
+            }
+        }
+    }
+}
+
+pub(crate) fn build(project_dir: &Path, target: &str, zip_paths: &[PathBuf], opt: &Opt) {
+    let target_arg = match opt.builder {
+        Builder::Zigbuild => format!("{}.{}", target, opt.glibc_version),
+        _ => target.to_owned(),
+    };
+    let subcommand = match opt.builder {
+        Builder::Zigbuild => "zigbuild",
+        _ => "build",
+    };
+    let mut args: Vec<String> = vec![subcommand.into()];
+    if opt.profile_name == "release" {
+        args.push("--release".into());
+    } else {
+        args.push("--profile".into());
+        args.push(opt.profile_name.clone());
+    }
+    args.push("--target".into());
+    args.push(target_arg);
+    for bin in &opt.bin {
+        args.push("--bin".into());
+        args.push(bin.clone());
+    }
+    args.extend(util::cargo_args(opt));
+
+    println!("Running cargo with args {}", args.join(" "));
+
+    let mut cmd = Command::new("cargo");
+    cmd.args(&args).current_dir(project_dir);
+    if !opt.keep_debug_info {
+        cmd.env(strip_env(&opt.profile_name), "symbols");
+    }
+    apply_env(&mut cmd, opt);
+
+    if !run_with_timeout(&mut cmd, opt) {
+        eprintln!("Running cargo {} failed, check output above", subcommand);
+        process::exit(1);
+    }
+
+    // cargo-zigbuild strips the glibc suffix from the target directory name
+    for (bin, zip_path) in opt.bin.iter().zip(zip_paths) {
+        let mut bin_path = util::target_dir(project_dir);
+        bin_path.extend(&[target, util::profile_dir(&opt.profile_name), bin]);
+        package(&bin_path, zip_path);
+    }
+}
+
+/// The variable setting `strip` of a cargo profile, e.g. `CARGO_PROFILE_BETA_LTO_STRIP` for
+/// `beta-lto`
+fn strip_env(profile_name: &str) -> String {
+    format!("CARGO_PROFILE_{}_STRIP", profile_name.to_ascii_uppercase().replace('-', "_"))
+}
+
+/// Runs `cargo test --release` for the target when the host can run its binaries, otherwise
+/// natively
+pub(crate) fn test(project_dir: &Path, target: &str, opt: &Opt) -> bool {
+    let mut args: Vec<String> = vec!["test".into(), "--release".into()];
+    let host_arch = target.starts_with(std::env::consts::ARCH) && std::env::consts::OS == "linux";
+    if host_arch {
+        args.push("--target".into());
+        args.push(target.to_owned());
+    } else {
+        println!("Warning: the host can't run {} binaries, running the tests natively", target);
+    }
+    args.extend(util::cargo_args(opt));
+    if !opt.test_args.is_empty() {
+        args.push("--".into());
+        args.extend(opt.test_args.iter().cloned());
+    }
+
+    println!("Running cargo with args {}", args.join(" "));
+
+    let mut cmd = Command::new("cargo");
+    cmd.args(&args).current_dir(project_dir);
+    apply_env(&mut cmd, opt);
+    cmd.status_bool()
+}
+
+/// Runs a quick `cargo check` of the binaries on the host, for the target when it's installed and
+/// natively otherwise. Only compile errors fail it, the check is skipped with a warning when the
+/// host can't build the project otherwise (e.g. without cargo or a C toolchain for a dependency).
+pub(crate) fn precheck(project_dir: &Path, target: &str, opt: &Opt) -> bool {
+    let mut args: Vec<String> = vec!["check".into()];
+    let installed = Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().any(|line| line.trim() == target))
+        .unwrap_or(false);
+    if installed {
+        args.push("--target".into());
+        args.push(target.to_owned());
+    }
+    for bin in &opt.bin {
+        args.push("--bin".into());
+        args.push(bin.clone());
+    }
+    args.extend(util::cargo_args(opt));
+
+    println!("Prechecking with cargo {}", args.join(" "));
+
+    let mut cmd = Command::new("cargo");
+    cmd.args(&args).current_dir(project_dir);
+    apply_env(&mut cmd, opt);
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(e) => {
+            println!("Warning: can't run cargo on the host, skipping the precheck: {}", e);
+            return true;
+        }
+    };
+    if output.status.success() {
+        return true;
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("could not compile") {
+        eprintln!("{}", stderr.trim_end());
+        return false;
+    }
+    println!("Warning: the host can't check the project, skipping the precheck:");
+    println!("{}", stderr.trim_end());
+    true
+}
+
+/// Runs cargo, killing it and exiting when it takes longer than --build-timeout
+fn run_with_timeout(cmd: &mut Command, opt: &Opt) -> bool {
+    let timeout = match opt.build_timeout {
+        Some(timeout) => timeout,
+        None => return cmd.status_bool(),
+    };
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to run cargo: {}", e);
+            return false;
+        }
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) => {}
+            Err(_) => return false,
+        }
+        if Instant::now() >= deadline {
+            eprintln!("Build timed out after {} s, killing cargo", timeout.as_secs());
+            child.kill().ok();
+            child.wait().ok();
+            process::exit(exit::TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Passes --env-file and -e variables to cargo
+fn apply_env(cmd: &mut Command, opt: &Opt) {
+    // Cargo detects the terminal itself unless --color says otherwise
+    if opt.color != "auto" {
+        cmd.env("CARGO_TERM_COLOR", &opt.color);
+    }
+    if let Some(ref toolchain) = opt.toolchain {
+        cmd.env("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    if let Some(ref env_file) = opt.env_file {
+        cmd.envs(util::read_env_file(env_file));
+    }
+    for env in &opt.env {
+        let mut parts = env.splitn(2, '=');
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            cmd.env(key, value);
+        }
+    }
+}
+
+/// Packs the executable as `bootstrap` into a zip like the docker image does
+fn package(bin_path: &Path, zip_path: &Path) {
+    let data = {
+        let mut bin_file = File::open(bin_path).expect("Can't open built binary");
+        let mut data = Vec::new();
+        bin_file.read_to_end(&mut data).unwrap();
+        data
+    };
+
+    if let Some(dir) = zip_path.parent() {
+        fs::create_dir_all(dir).expect("Can't create zip directory");
+    }
+
+    let zip_file = File::create(zip_path).expect("Can't create zip file");
+    let mut zip = ZipWriter::new(zip_file);
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o755);
+    zip.start_file("bootstrap", options).expect("Can't write zip file");
+    zip.write_all(&data).expect("Can't write zip file");
+    zip.finish().expect("Can't write zip file");
+
+    println!("Packaged {} into {}", bin_path.display(), zip_path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_built_profile() {
+        assert_eq!(strip_env("release"), "CARGO_PROFILE_RELEASE_STRIP");
+        assert_eq!(strip_env("dev"), "CARGO_PROFILE_DEV_STRIP");
+        assert_eq!(strip_env("beta-lto"), "CARGO_PROFILE_BETA_LTO_STRIP");
+    }
+}
@@ -1,80 +1,131 @@
-use rusoto_core::credential::{ChainProvider, ProfileProvider, StaticProvider};
-use rusoto_core::{HttpClient, Region};
-use rusoto_logs::{CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsRequest};
-use std::collections::HashSet;
-use std::str::FromStr;
+use rusoto_core::RusotoError;
+use rusoto_logs::{CloudWatchLogs, CloudWatchLogsClient, FilterLogEventsError, FilterLogEventsRequest};
+use std::collections::HashMap;
+use std::process;
 use std::time::{Duration, SystemTime};
-use crate::Opt;
+use crate::{AwsOpt, TailOpt};
+use crate::{aws, exit};
+use crate::report::{self, PlatformLine};
 
-pub(crate) fn create_client(opt: &Opt, region: &str) -> CloudWatchLogsClient {
-    let dispatcher = HttpClient::new().expect("failed to create request dispatcher");
-    let region = Region::from_str(region).unwrap();
-
-    match (&opt.access_key, &opt.secret_key, &opt.profile) {
-        (Some(access_key), Some(secret_key), _) => {
-            let creds = StaticProvider::new_minimal(access_key.to_owned(), secret_key.to_owned());
-            CloudWatchLogsClient::new_with(dispatcher, creds, region)
-        },
-        (_, _, Some(profile)) => {
-            let mut creds = ProfileProvider::new().unwrap();
-            creds.set_profile(profile.to_owned());
-            CloudWatchLogsClient::new_with(dispatcher, creds, region)
-        },
-        _ => {
-            let creds = ChainProvider::new();
-            CloudWatchLogsClient::new_with(dispatcher, creds, region)
-        }
-    }
+pub(crate) fn create_client(opt: &AwsOpt, region: &str) -> CloudWatchLogsClient {
+    aws::client(opt, region, CloudWatchLogsClient::new_with_client)
 }
 
-pub fn tail(
-    logs_client: &CloudWatchLogsClient,
+/// Times in a row expired credentials are refreshed before giving up, e.g. when the refreshed ones
+/// are expired as well
+const MAX_REFRESHES: u32 = 3;
+
+/// Tails the function's logs, recreating the client to refresh expired temporary credentials
+#[allow(clippy::result_large_err)]
+pub(crate) fn tail(
+    aws: &AwsOpt,
+    region: &str,
     function_name: &str,
-) -> Result<(), Box<dyn ::std::error::Error>> {
-    let unix = || {
+    opt: &TailOpt,
+) -> Result<(), RusotoError<FilterLogEventsError>> {
+    let mut logs_client = create_client(aws, region);
+    let now = || {
         SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis() as i64
-            - 5 * 60 * 1000
     };
-    let user_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as i64;
-    let mut next_token = None;
-    let mut start_time = Some(unix());
-    let mut seen = HashSet::new();
+    let log_group_name = format!("/aws/lambda/{}", function_name);
+    // Events show up late, so each window reaches this far back and the printed ones are skipped
+    let lag = 5 * 60 * 1000;
+    // From the epoch at the earliest, e.g. for --since 100000000h
+    let user_time = now().saturating_sub(opt.since.as_millis().min(i64::MAX as u128) as i64).max(0);
+    let mut start_time = (now() - lag).min(user_time);
+    let mut seen = HashMap::new();
+    let mut refreshes = 0;
 
     loop {
-        let input = FilterLogEventsRequest {
-            end_time: None,
-            filter_pattern: None,
-            limit: Some(10000),
-            log_group_name: format!("/aws/lambda/{}", function_name),
-            log_stream_name_prefix: None,
-            log_stream_names: None,
-            next_token: next_token.clone(),
-            start_time,
-        };
+        let end_time = now();
+        let mut next_token = None;
+        // Every page of the window is drained before sleeping, so no events are dropped
+        loop {
+            let input = FilterLogEventsRequest {
+                end_time: Some(end_time),
+                filter_pattern: opt.filter.clone(),
+                limit: None,
+                log_group_name: log_group_name.clone(),
+                log_stream_name_prefix: None,
+                log_stream_names: None,
+                next_token: next_token.clone(),
+                start_time: Some(start_time),
+            };
 
-        let res = logs_client.filter_log_events(input).sync()?;
+            let res = match logs_client.filter_log_events(input).sync() {
+                Ok(res) => res,
+                Err(ref e) if credentials_expired(e) && refreshes < MAX_REFRESHES => {
+                    println!("[cargo-aws-lambda] Credentials expired, refreshing them and continuing");
+                    refreshes += 1;
+                    logs_client = create_client(aws, region);
+                    ::std::thread::sleep(Duration::from_secs(opt.tail_interval));
+                    continue;
+                }
+                Err(ref e) if credentials_expired(e) => {
+                    eprintln!(
+                        "Credentials are still expired after refreshing them {} times, renew them \
+                         (e.g. aws sso login) and tail again:\n{:?}",
+                        MAX_REFRESHES, e
+                    );
+                    process::exit(exit::CREDENTIALS);
+                }
+                Err(e) => return Err(e),
+            };
+            refreshes = 0;
 
-        if let Some(events) = res.events {
-            for event in events {
-                let ts = event.timestamp.unwrap_or(::std::i64::MAX);
-                if !seen.contains(event.event_id.as_ref().unwrap()) && ts > user_time {
-                    print!("{}", event.message.unwrap());
-                    seen.insert(event.event_id.unwrap().clone());
+            for event in res.events.unwrap_or_default() {
+                let ts = event.timestamp.unwrap_or(i64::MAX);
+                let event_id = event.event_id.unwrap_or_default();
+                if ts > user_time && !seen.contains_key(&event_id) {
+                    print_event(ts, &event.message.unwrap_or_default(), opt);
+                    seen.insert(event_id, ts);
                 }
             }
+
+            next_token = res.next_token;
+            if next_token.is_none() {
+                break;
+            }
         }
 
-        next_token = res.next_token;
+        start_time = end_time - lag;
+        // Older events aren't in the next windows anymore
+        seen.retain(|_, ts| *ts >= start_time);
+        ::std::thread::sleep(Duration::from_secs(opt.tail_interval));
+    }
+}
+
+/// Prints the message as is, as a JSON line with --json or only its REPORT metrics with
+/// --report-only
+fn print_event(timestamp: i64, message: &str, opt: &TailOpt) {
+    let line = report::parse(message);
+    if opt.report_only {
+        if let Some(PlatformLine::Report(ref report)) = line {
+            if opt.json {
+                println!("{}", serde_json::to_string(report).unwrap());
+            } else {
+                println!("{}", report::report_text(report));
+            }
+        }
+    } else if opt.json {
+        println!("{}", report::event_json(timestamp, message, line.as_ref()));
+    } else {
+        print!("{}", message);
+    }
+}
 
-        if next_token.is_none() {
-            start_time = Some(unix());
+/// Whether the temporary credentials expired, which refreshing them fixes. Credentials that can't
+/// be loaded at all (e.g. a missing profile) or are rejected otherwise aren't retried.
+fn credentials_expired<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Credentials(e) => e.message.to_lowercase().contains("expired"),
+        RusotoError::Unknown(res) => {
+            let body = String::from_utf8_lossy(&res.body);
+            body.contains("ExpiredToken") || body.contains("security token included in the request is expired")
         }
-        ::std::thread::sleep(Duration::from_millis(3000));
+        _ => false,
     }
 }
@@ -1,21 +1,109 @@
-use std::fs::File;
-use std::io::Read;
-use std::path::{PathBuf};
+use std::fs::{self, File};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::{env, process};
+use std::{env, process, thread};
 use structopt::StructOpt;
 use std::fmt::Display;
-use util::CommandExt;
-use rusoto_lambda::{UpdateFunctionCodeRequest, Lambda};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use serde_json::json;
+use rusoto_lambda::{FunctionConfiguration, UpdateFunctionCodeRequest, Lambda, LambdaClient};
+use crate::util::CommandExt;
 
+mod artifacts;
+mod assets;
+mod aws;
+mod aws_log;
+mod checks;
 mod docker;
+mod exit;
+mod hooks;
+mod http;
 mod lambda;
+mod local;
 mod logs;
+mod package;
+mod progress;
+mod report;
+mod secrets;
+mod signing;
+mod size;
+mod strip;
+mod timing;
 mod util;
+mod watch;
+
+/// Backend used to compile the binary
+#[derive(Debug)]
+enum Builder {
+    Docker,
+    Zigbuild,
+    Native,
+}
+
+impl FromStr for Builder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Builder::Docker),
+            "zigbuild" => Ok(Builder::Zigbuild),
+            "native" => Ok(Builder::Native),
+            _ => Err(format!("Unknown builder {}", s)),
+        }
+    }
+}
+
+/// CPU architecture of the Lambda function
+#[derive(Debug, PartialEq)]
+enum Arch {
+    X86_64,
+    Arm64,
+}
+
+impl Arch {
+    fn triple(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64-unknown-linux-gnu",
+            Arch::Arm64 => "aarch64-unknown-linux-gnu",
+        }
+    }
+}
+
+impl FromStr for Arch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" => Ok(Arch::X86_64),
+            "arm64" => Ok(Arch::Arm64),
+            _ => Err(format!("Unknown architecture {}", s)),
+        }
+    }
+}
 
 /// Packages and deploys your project binaries to AWS Lambda
+// Parsed once, so the size of Opt doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
-struct Opt {
+enum Cli {
+    /// Build and deploy a binary (the default when no subcommand is given)
+    Deploy(Opt),
+    /// Tail a function's cloudwatch logs without deploying
+    Logs(LogsOpt),
+    /// List managed build volumes and remove the ones whose project no longer exists
+    Gc(GcOpt),
+}
+
+const SUBCOMMANDS: &[&str] = &["deploy", "logs", "gc", "help", "-h", "--help", "-V", "--version"];
+
+// Doc comments on flattened structs would override the subcommand descriptions
+
+// AWS credential options
+#[derive(StructOpt, Debug)]
+struct AwsOpt {
     /// AWS Profile
     #[structopt(long)]
     profile: Option<String>,
@@ -25,124 +113,1732 @@ struct Opt {
     /// AWS Secret Key
     #[structopt(long)]
     secret_key: Option<String>,
+    /// Use only the EC2 instance role credentials, ignoring environment variables and profiles
+    #[structopt(long, conflicts_with_all = &["profile", "access-key", "secret-key"])]
+    instance_role: bool,
+    /// Log the AWS requests and responses (with credentials redacted) for debugging
+    #[structopt(long)]
+    debug_aws: bool,
+    /// Fail AWS requests that take longer than this many seconds, e.g. hung connections
+    #[structopt(long, default_value = "60")]
+    aws_timeout: u64,
+}
+
+// Log tailing options
+#[derive(StructOpt, Debug)]
+struct TailOpt {
+    /// CloudWatch Logs filter pattern for the tailed events
+    #[structopt(long)]
+    filter: Option<String>,
+    /// Also show events from this far back (e.g. 30s, 5m or 1h)
+    #[structopt(long, default_value = "0s", parse(try_from_str = util::parse_duration))]
+    since: Duration,
+    /// Seconds to wait between polls for new events
+    #[structopt(long, default_value = "3")]
+    tail_interval: u64,
+    /// Print the events as JSON lines, with the START, END and REPORT fields broken out
+    #[structopt(long)]
+    json: bool,
+    /// Print only the metrics of the REPORT lines
+    #[structopt(long)]
+    report_only: bool,
+}
+
+// Options of the deploy subcommand
+#[derive(StructOpt, Debug)]
+struct Opt {
+    #[structopt(flatten)]
+    aws: AwsOpt,
     /// Full ARN of the function to deploy or its configuration key in table [arns] in Lambda.toml
-    /// (e.g. arn:aws:lambda:eu-north-1:1234:function:MyLambdaFunc)
-    #[structopt(name = "FUNCTION_ARN")]
+    /// (e.g. arn:aws:lambda:eu-north-1:1234:function:MyLambdaFunc, defaults to LAMBDA_FUNCTION_ARN
+    /// or `default` in Lambda.toml)
+    #[structopt(name = "FUNCTION_ARN", env = "LAMBDA_FUNCTION_ARN")]
+    arn_arg: Option<String>,
+    /// FUNCTION_ARN or its default
+    #[structopt(skip)]
     arn: String,
-    /// Project binary to deploy
+    /// Lambda.toml to use instead of the one in the current directory or workspace root
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Deploy to the function in this region instead of the ARN's (repeatable, the same zip goes to
+    /// each region and FUNCTION_ARN can then be just the function name)
+    #[structopt(long = "region", number_of_values = 1)]
+    regions: Vec<String>,
+    /// Project binaries to deploy, several are built in one go and each is deployed to its entry in
+    /// table [arns] in Lambda.toml, can be left out when the project has only one
     /// (e.g. `mylambdafunc`, if you have src/bin/mylambdafunc.rs with a main function in your project)
     #[structopt(name = "BIN")]
-    bin: String,
+    bin: Vec<String>,
+    /// Deploy all binaries of the project, each to its entry in table [arns] in Lambda.toml
+    #[structopt(long)]
+    all_bins: bool,
+    /// Don't check that BIN is a bin target of the project before building
+    #[structopt(long, alias = "skip-bin-check")]
+    skip_target_check: bool,
+    /// Path to Cargo.toml of the crate to deploy, e.g. a workspace member (defaults to the one in cwd)
+    #[structopt(long, parse(from_os_str))]
+    manifest_path: Option<PathBuf>,
+    /// Workspace package containing the binary, forwarded to cargo as --package (defaults to the
+    /// package of the --manifest-path or cwd crate when it's a workspace member)
+    #[structopt(short, long)]
+    package: Option<String>,
+    /// Path of the built zip, for setups where it can't be derived from the target directory
+    #[structopt(long, parse(from_os_str))]
+    zip_path: Option<PathBuf>,
+    /// Copy the built zip into this directory and deploy it from there
+    #[structopt(long, parse(from_os_str))]
+    zip_dir: Option<PathBuf>,
+    /// Keep each built zip in this directory as <bin>-<git sha>-<unix time>.zip, with <bin>.zip
+    /// pointing at the newest, and deploy it from there
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["output-zip", "signed-object", "image-uri"])]
+    artifact_dir: Option<PathBuf>,
+    /// Remove all but the newest N zips of each binary in --artifact-dir
+    #[structopt(long, requires = "artifact-dir")]
+    keep_artifacts: Option<usize>,
+    /// Write a JSON record of the deploy to this path, also when it fails
+    #[structopt(long, parse(from_os_str))]
+    report_file: Option<PathBuf>,
+    /// Copy the built zip to this path, deploy it from there and print the path as OUTPUT_ZIP=<path>
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["zip-dir", "signed-object"])]
+    output_zip: Option<PathBuf>,
+    /// Cargo features to enable (repeatable or comma separated, added to features in table [build.<key>]
+    /// of Lambda.toml)
+    #[structopt(long, number_of_values = 1, use_delimiter = true)]
+    features: Vec<String>,
+    /// Enable all cargo features
+    #[structopt(long)]
+    all_features: bool,
+    /// Don't enable the default cargo features
+    #[structopt(long)]
+    no_default_features: bool,
+    /// Build with --locked, failing if Cargo.lock needs updating
+    #[structopt(long)]
+    locked: bool,
+    /// Build with --offline from vendor/ or the cargo registry cache, without network in the container
+    #[structopt(long)]
+    offline: bool,
+    /// Extra flag for cargo build (repeatable, e.g. --cargo-flag=--offline)
+    #[structopt(long = "cargo-flag", number_of_values = 1, allow_hyphen_values = true)]
+    cargo_flags: Vec<String>,
+    /// Cargo profile to build with (e.g. dev or a custom one, not to be confused with --profile
+    /// for AWS)
+    #[structopt(long, default_value = "release")]
+    profile_name: String,
     /// Retain debug info in executable (for backtraces etc.)
     #[structopt(long)]
     keep_debug_info: bool,
-    /// Override docker image with your own
-    #[structopt(long, default_value = "softprops/lambda-rust:latest")]
+    /// Keep the zip as the build wrote it instead of rewriting it with fixed timestamps and order
+    #[structopt(long)]
+    no_normalize_zip: bool,
+    /// Strip the symbol tables from the built binary and zip it again, printing the saving
+    #[structopt(long, conflicts_with = "keep-debug-info")]
+    strip: bool,
+    /// Rust toolchain to build with (defaults to the channel in rust-toolchain.toml)
+    #[structopt(long)]
+    toolchain: Option<String>,
+    /// Architecture to build for (x86_64 or arm64 for Graviton)
+    #[structopt(long, default_value = "x86_64", possible_values = &["x86_64", "arm64"])]
+    arch: Arch,
+    /// Target triple to build for, overrides --arch (e.g. aarch64-unknown-linux-gnu)
+    #[structopt(long)]
+    target: Option<String>,
+    /// Build backend (docker, zigbuild or native cargo on the host)
+    #[structopt(long, default_value = "docker", possible_values = &["docker", "zigbuild", "native"])]
+    builder: Builder,
+    /// glibc version to link against when using the zigbuild builder
+    #[structopt(long, default_value = "2.26")]
+    glibc_version: String,
+    /// Container runtime binary used with the docker builder (e.g. podman)
+    #[structopt(long, env = "CARGO_AWS_LAMBDA_RUNTIME", default_value = "docker")]
+    container_runtime: String,
+    /// Platform of the build container, defaults to linux/amd64 (linux/arm64 for arm64 targets)
+    #[structopt(long)]
+    docker_platform: Option<String>,
+    /// Override docker image with your own, pin it with a digest for reproducible builds
+    /// (e.g. softprops/lambda-rust@sha256:..., defaults to the image for the function's runtime)
+    #[structopt(long = "docker-image")]
+    docker_image_override: Option<String>,
+    /// The image the build runs in, --docker-image or the one picked for the runtime
+    #[structopt(skip)]
     docker_image: String,
+    /// Build the image from this Dockerfile (with the current directory as the context) and use it
+    /// instead of --docker-image
+    #[structopt(long, parse(from_os_str))]
+    dockerfile: Option<PathBuf>,
+    /// When to pull the docker image before building
+    #[structopt(long, default_value = "missing", possible_values = &["always", "missing", "never"])]
+    pull: String,
+    /// Command that must succeed before building, `clippy` and `audit` run the cargo tools
+    /// (repeatable, added to the `checks` list in Lambda.toml)
+    #[structopt(long = "check", number_of_values = 1)]
+    checks: Vec<String>,
+    /// Skip the checks, for emergencies
+    #[structopt(long)]
+    skip_checks: bool,
+    /// Run a quick `cargo check` on the host first and don't build if it fails
+    #[structopt(long)]
+    precheck: bool,
+    /// Run `cargo test --release` in the build environment first and don't deploy if it fails
+    #[structopt(long)]
+    test: bool,
+    /// Arguments passed to the test binaries with --test (e.g. --test-args integration::)
+    #[structopt(long, number_of_values = 1, allow_hyphen_values = true, requires = "test")]
+    test_args: Vec<String>,
+    /// Pass the build output through as is instead of condensing it
+    #[structopt(long)]
+    raw_build_output: bool,
+    /// Color our output and cargo's
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"])]
+    color: String,
+    /// Passed to cargo as --message-format, json diagnostics are printed as is
+    #[structopt(long, possible_values = &["human", "short", "json"])]
+    message_format: Option<String>,
+    /// Warn when less than this many GB are free on the docker disk or for the target directory
+    #[structopt(long, default_value = "2")]
+    min_free_space: f64,
+    /// Refuse to build when there's less than --min-free-space free
+    #[structopt(long)]
+    strict_disk: bool,
+    /// Print details of the build environment, like the free disk space
+    #[structopt(long)]
+    verbose: bool,
+    /// Kill the build container (or cargo without docker) if the build takes longer than this
+    /// (e.g. 30m)
+    #[structopt(long, parse(try_from_str = util::parse_duration))]
+    build_timeout: Option<Duration>,
+    /// Retry the build container this many times on transient docker failures, e.g. network
+    /// errors while pulling the image (compiler errors are never retried)
+    #[structopt(long, default_value = "2")]
+    docker_retries: u32,
     /// Dry-run (compile and deploy in dry-run mode)
     #[structopt(long)]
     dry_run: bool,
+    /// Update $LATEST in place without publishing a new version
+    #[structopt(long)]
+    no_publish: bool,
+    /// Build even when nothing changed since the last successful deploy
+    #[structopt(long)]
+    force_build: bool,
+    /// Don't upload the zip when the function already runs the same code
+    #[structopt(long)]
+    skip_unchanged: bool,
+    /// Build and deploy again whenever the watched paths change
+    #[structopt(long)]
+    watch: bool,
+    /// Path to watch with --watch, relative to the crate (repeatable, defaults to src, Cargo.toml
+    /// and Cargo.lock)
+    #[structopt(long = "watch-path", number_of_values = 1, parse(from_os_str), requires = "watch")]
+    watch_paths: Vec<PathBuf>,
+    /// Description of the published version (defaults to the git commit)
+    #[structopt(long)]
+    description: Option<String>,
+    /// Don't pass GIT_SHA, GIT_DIRTY, GIT_BRANCH and BUILD_TIMESTAMP to the build
+    #[structopt(long)]
+    no_git_metadata: bool,
     /// Use managed persistent build volume (speeds things up on windows hosts)
     #[structopt(long)]
     use_build_volume: bool,
+    /// Hide a project directory (e.g. target) from the container, the zip output is kept on the host
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Give files written by the build container back to the host user (auto enables it on linux)
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "on", "off"])]
+    user_mapping: String,
+    /// Print the docker command that would build the project and exit without running it
+    #[structopt(long)]
+    print_docker_command: bool,
+    /// Keep the build container after the build for debugging
+    #[structopt(long)]
+    keep_container: bool,
+    /// Remove stopped build containers left by --keep-container and exit
+    #[structopt(long)]
+    gc_containers: bool,
+    /// List the functions in the region of FUNCTION_ARN (or each --region) and exit
+    #[structopt(long)]
+    list_functions: bool,
+    /// Remove the project's build volume and exit
+    #[structopt(long)]
+    prune_build_volume: bool,
+    /// Remove and recreate the project's build volume before building (implies --use-build-volume)
+    #[structopt(long)]
+    recreate_build_volume: bool,
+    /// Use this build volume instead of one generated from the project path (e.g. to share one)
+    #[structopt(long)]
+    build_volume_name: Option<String>,
+    /// Cache compiled crates with sccache in the build container (the image must have sccache)
+    #[structopt(long)]
+    sccache: bool,
+    /// Host directory for the sccache cache (defaults to ~/.cache/cargo-aws-lambda/sccache)
+    #[structopt(long, parse(from_os_str), requires = "sccache")]
+    sccache_dir: Option<PathBuf>,
+    /// Forward the host's SSH agent into the build container for private git dependencies
+    #[structopt(long)]
+    ssh_agent: bool,
+    /// .git-credentials file for private git dependencies over HTTPS, fetched with the git CLI
+    #[structopt(long, parse(from_os_str))]
+    git_credentials: Option<PathBuf>,
+    /// Bind mount the project even when DOCKER_HOST points to a remote daemon
+    #[structopt(long)]
+    force_mount: bool,
+    /// Extra argument for docker run, inserted verbatim before the image name (repeatable,
+    /// appended to docker_args in Lambda.toml)
+    #[structopt(long = "docker-arg", number_of_values = 1)]
+    docker_args: Vec<String>,
+    /// Command to run in the build container instead of the image's build script, one argument
+    /// per flag (defaults to docker_cmd in Lambda.toml)
+    #[structopt(long = "docker-cmd", number_of_values = 1, allow_hyphen_values = true)]
+    docker_cmd: Vec<String>,
+    /// Memory limit of the build container, e.g. 6g (defaults to memory in table [build.<key>] of
+    /// Lambda.toml)
+    #[structopt(long)]
+    build_memory: Option<String>,
+    /// CPU limit of the build container, e.g. 2.5 (defaults to cpus in table [build.<key>] of
+    /// Lambda.toml)
+    #[structopt(long)]
+    build_cpus: Option<f64>,
+    /// Pass environment variables from a KEY=VALUE file to the build (defaults to the function's
+    /// entry in table [env_files] in Lambda.toml)
+    #[structopt(long, parse(from_os_str))]
+    env_file: Option<PathBuf>,
     /// Pass environment variables to the container (for eg. -e RUSTFLAGS=-Ztime-passes)
     #[structopt(short, long)]
     env: Vec<String>,
+    /// Sign the zip with this AWS Signer profile (name or ARN) before deploying
+    #[structopt(long)]
+    signing_profile: Option<String>,
+    /// Versioned S3 bucket used for the unsigned and signed zips when signing
+    #[structopt(long)]
+    signing_bucket: Option<String>,
+    /// Deploy this container image (an ECR image URI) instead of building a zip
+    #[structopt(
+        long,
+        conflicts_with_all = &["signed-object", "zip-path", "output-zip", "strip", "watch", "test"]
+    )]
+    image_uri: Option<String>,
+    /// Deploy an already signed object (s3://bucket/key) instead of building
+    #[structopt(long, conflicts_with = "signing-profile")]
+    signed_object: Option<String>,
+    /// Change the function's runtime (e.g. provided.al2), asks for confirmation unless --yes is given
+    #[structopt(long, possible_values = lambda::RUNTIMES)]
+    runtime: Option<String>,
+    /// Don't ask for confirmation
+    #[structopt(short, long)]
+    yes: bool,
+    /// Set the function's handler (e.g. bootstrap for provided runtimes)
+    #[structopt(long, parse(try_from_str = util::parse_non_empty))]
+    handler: Option<String>,
+    /// Subnet to run the function in (repeatable, requires --security-group)
+    #[structopt(long = "subnet", number_of_values = 1, requires = "security-groups")]
+    subnets: Vec<String>,
+    /// Security group for the function in a VPC (repeatable, requires --subnet)
+    #[structopt(long = "security-group", number_of_values = 1, requires = "subnets")]
+    security_groups: Vec<String>,
+    /// Set an environment variable of the function, keeping the others (repeatable, KEY=VALUE)
+    #[structopt(
+        long = "lambda-env",
+        number_of_values = 1,
+        parse(try_from_str = util::parse_key_value)
+    )]
+    lambda_env: Vec<String>,
+    /// Set the function's environment variables from a .env file, keeping the others (--lambda-env
+    /// takes precedence)
+    #[structopt(long, parse(from_os_str))]
+    lambda_env_file: Option<PathBuf>,
+    /// Set an environment variable of the function to the value of a Secrets Manager secret
+    /// (repeatable, KEY=SECRET-ARN, takes precedence over --lambda-env)
+    #[structopt(
+        long = "lambda-env-secret",
+        number_of_values = 1,
+        parse(try_from_str = util::parse_key_value)
+    )]
+    lambda_env_secrets: Vec<String>,
+    /// Values read for --lambda-env-secret, masked in the output
+    #[structopt(skip)]
+    lambda_env_secret_values: HashMap<String, String>,
+    /// Send failed asynchronous invocations to this SQS queue or SNS topic ARN
+    #[structopt(long, parse(try_from_str = util::parse_dlq_arn))]
+    dlq: Option<String>,
+    /// Remove the function's dead-letter queue
+    #[structopt(long, conflicts_with = "dlq")]
+    no_dlq: bool,
+    /// Set the function's X-Ray tracing mode
+    #[structopt(long, possible_values = &["Active", "PassThrough"])]
+    tracing: Option<String>,
+    /// Reserve concurrency for the function (caps its concurrent executions)
+    #[structopt(long, conflicts_with = "no-reserved-concurrency")]
+    reserved_concurrency: Option<i64>,
+    /// Remove reserved concurrency from the function
+    #[structopt(long)]
+    no_reserved_concurrency: bool,
+    /// Create or update the function's URL with this auth type
+    #[structopt(long, possible_values = &["NONE", "AWS_IAM"])]
+    function_url: Option<String>,
+    /// Origin allowed to call the function URL (repeatable, e.g. https://example.com or *)
+    #[structopt(long = "cors-allow-origin", number_of_values = 1, requires = "function-url")]
+    cors_allow_origins: Vec<String>,
+    /// HTTP method allowed in CORS requests to the function URL (repeatable)
+    #[structopt(long = "cors-allow-method", number_of_values = 1, requires = "function-url")]
+    cors_allow_methods: Vec<String>,
+    /// Header allowed in CORS requests to the function URL (repeatable)
+    #[structopt(long = "cors-allow-header", number_of_values = 1, requires = "function-url")]
+    cors_allow_headers: Vec<String>,
+    /// Let CORS requests to the function URL include credentials
+    #[structopt(long, requires = "function-url")]
+    cors_allow_credentials: bool,
+    /// Seconds browsers may cache the CORS preflight of the function URL
+    #[structopt(long, requires = "function-url")]
+    cors_max_age: Option<i64>,
+    /// Provision concurrency for the published version
+    #[structopt(long, conflicts_with_all = &["no-publish", "dry-run"])]
+    provisioned_concurrency: Option<i64>,
+    /// Wait until the provisioned concurrency is ready
+    #[structopt(long, requires = "provisioned-concurrency")]
+    wait: bool,
+    /// Give up --wait after this long (e.g. 90s or 15m)
+    #[structopt(long, default_value = "15m", parse(try_from_str = util::parse_duration))]
+    wait_timeout: Duration,
+    /// Invoke the function with this JSON payload after deploying and fail on a function error
+    #[structopt(long, parse(try_from_str = util::parse_json))]
+    invoke: Option<String>,
+    /// Like --invoke with the payload read from a file
+    #[structopt(long, parse(from_os_str), conflicts_with = "invoke")]
+    invoke_file: Option<PathBuf>,
+    /// Compare the zip size with this zip or file with a size in bytes instead of the last deploy
+    #[structopt(long, parse(from_os_str))]
+    size_baseline: Option<PathBuf>,
+    /// Warn when the zip grew more than this many percent
+    #[structopt(long, default_value = "10")]
+    size_threshold: f64,
+    /// List the largest sections of the binary
+    #[structopt(long)]
+    size_details: bool,
     /// Tail function's cloudwatch logs
     #[structopt(long)]
     tail_logs: bool,
+    #[structopt(flatten)]
+    tail: TailOpt,
 }
 
-fn main() {
-    docker::check();
+// Options of the logs subcommand
+#[derive(StructOpt, Debug)]
+struct LogsOpt {
+    #[structopt(flatten)]
+    aws: AwsOpt,
+    /// Full ARN of the function or its configuration key in table [arns] in Lambda.toml (defaults
+    /// to LAMBDA_FUNCTION_ARN or `default` in Lambda.toml)
+    #[structopt(name = "FUNCTION_ARN", env = "LAMBDA_FUNCTION_ARN")]
+    arn_arg: Option<String>,
+    /// FUNCTION_ARN or its default
+    #[structopt(skip)]
+    arn: String,
+    /// Lambda.toml to use instead of the one in the current directory or workspace root
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(flatten)]
+    tail: TailOpt,
+}
+
+// Options of the gc subcommand
+#[derive(StructOpt, Debug)]
+struct GcOpt {
+    /// Container runtime binary (e.g. podman)
+    #[structopt(long, env = "CARGO_AWS_LAMBDA_RUNTIME", default_value = "docker")]
+    container_runtime: String,
+    /// Remove without asking for confirmation
+    #[structopt(short, long)]
+    yes: bool,
+}
 
+fn main() {
     let mut args = env::args().collect::<Vec<_>>();
-    args.remove(1);
-    let opt = Opt::from_iter(args);
+    // Cargo passes the subcommand name on, running cargo-aws-lambda directly doesn't
+    if args.get(1).map(String::as_str) == Some("aws-lambda") {
+        args.remove(1);
+    }
+    // Keep `cargo aws-lambda <ARN> <BIN>` working without the deploy subcommand
+    let has_subcommand = args
+        .get(1)
+        .map(|arg| SUBCOMMANDS.contains(&arg.as_str()))
+        .unwrap_or(false);
+    if !has_subcommand {
+        args.insert(1, "deploy".into());
+    }
+
+    let mut cli = Cli::from_iter(args.clone());
+    if let Cli::Deploy(Opt { ref aws, ref config, .. }) | Cli::Logs(LogsOpt { ref aws, ref config, .. }) =
+        cli
+    {
+        if aws.debug_aws {
+            aws_log::init();
+        }
+        if let Some(config) = config {
+            util::set_lambda_toml(config);
+        }
+    }
+
+    match cli {
+        Cli::Deploy(ref mut opt) => opt.arn = resolve_arn(opt.arn_arg.take()),
+        Cli::Logs(ref mut opt) => opt.arn = resolve_arn(opt.arn_arg.take()),
+        Cli::Gc(_) => {}
+    }
 
-    if opt.use_build_volume {
-        docker::manage_build_volume();
+    match cli {
+        Cli::Deploy(ref opt) if opt.watch => watch::run(opt, &args),
+        Cli::Deploy(opt) => deploy(opt),
+        Cli::Logs(opt) => tail_logs(opt),
+        Cli::Gc(opt) => {
+            docker::check(&opt.container_runtime);
+            docker::gc_volumes(&opt.container_runtime, opt.yes);
+        }
     }
+}
 
-    let zip_file = format!("{}.zip", opt.bin);
+/// FUNCTION_ARN (given or from LAMBDA_FUNCTION_ARN) or `default` in Lambda.toml
+fn resolve_arn(arn: Option<String>) -> String {
+    let default = || util::lambda_toml().default.clone();
+    arn.or_else(default).unwrap_or_else(|| {
+        eprintln!(
+            "No function given, pass FUNCTION_ARN, set LAMBDA_FUNCTION_ARN or set default in \
+             Lambda.toml"
+        );
+        process::exit(exit::BAD_ARGS);
+    })
+}
+
+fn tail_logs(opt: LogsOpt) {
     let (region, func_name) = util::parse_arn_or_key(&opt.arn);
-    let project_dir = env::current_dir().expect("Can't read cwd.");
+    println!("===== Tailing logs of {} in {} =====", func_name, region);
+    if let Err(e) = logs::tail(&opt.aws, &region, &func_name, &opt.tail) {
+        eprintln!("Failed to tail logs:\n{:?}", e);
+        process::exit(exit::for_rusoto_error(&e));
+    }
+}
 
-    let mut zip_path = project_dir.clone();
-    zip_path.extend(&["target", "lambda", "release", &zip_file]);
+fn deploy(mut opt: Opt) {
+    let mut docker_args = util::lambda_toml().docker_args.clone();
+    docker_args.append(&mut opt.docker_args);
+    opt.docker_args = docker_args;
+    if opt.docker_cmd.is_empty() {
+        opt.docker_cmd = util::lambda_toml().docker_cmd.clone();
+    }
+    if let Some(ref runtime) = opt.runtime {
+        println!(
+            "Warning: changing the runtime to {} breaks the function if the binary isn't built for it",
+            runtime
+        );
+        if !opt.yes && !io::stdin().is_terminal() {
+            eprintln!("Changing the runtime needs confirmation, pass --yes to run without a terminal");
+            process::exit(exit::BAD_ARGS);
+        }
+        if !opt.yes && !util::confirm("Continue?") {
+            eprintln!("Aborted");
+            process::exit(1);
+        }
+    }
 
-    println!(
-        "Preparing to deploy {} to {:?} {}",
-        zip_path.display(),
-        region,
-        func_name
-    );
+    if let Some(build) = util::lambda_toml().build.get(&opt.arn) {
+        let mut features = build.features.clone();
+        features.append(&mut opt.features);
+        opt.features = features;
+        opt.all_features |= build.all_features;
+        opt.no_default_features |= build.no_default_features;
+        if opt.build_memory.is_none() {
+            opt.build_memory = build.memory.clone();
+        }
+        if opt.build_cpus.is_none() {
+            opt.build_cpus = build.cpus;
+        }
+    }
+    if opt.env_file.is_none() {
+        opt.env_file = util::lambda_toml().env_files.get(&opt.arn).map(PathBuf::from);
+    }
+    docker::check_excludes(&opt);
+    if let Some(ref env_file) = opt.env_file {
+        // Fail here rather than with a docker error when the file is missing
+        util::read_env_file(env_file);
+    }
+    if let Some(ref lambda_env_file) = opt.lambda_env_file {
+        // Fail before building rather than after
+        util::read_dotenv_file(lambda_env_file);
+    }
+    if let Some(ref invoke_file) = opt.invoke_file {
+        // Fail before building rather than after deploying
+        let payload = fs::read_to_string(invoke_file).unwrap_or_else(|e| {
+            eprintln!("Can't read --invoke-file {}: {}", invoke_file.display(), e);
+            process::exit(exit::BAD_ARGS);
+        });
+        if let Err(e) = util::parse_json(&payload) {
+            eprintln!("--invoke-file {}: {}", invoke_file.display(), e);
+            process::exit(exit::BAD_ARGS);
+        }
+        opt.invoke = Some(payload);
+    }
+    if !opt.lambda_env_secrets.is_empty() {
+        // Fail before building when a secret can't be read
+        let region = util::try_function_region(&opt.arn, &opt.regions).map(|(region, _)| region);
+        opt.lambda_env_secret_values =
+            secrets::resolve(&opt.aws, &opt.lambda_env_secrets, region.as_deref());
+    }
+
+    if opt.list_functions {
+        list_functions(&opt);
+        return;
+    }
+
+    if opt.gc_containers {
+        docker::check(&opt.container_runtime);
+        docker::gc_containers(&opt);
+        return;
+    }
+
+    if opt.prune_build_volume {
+        docker::check(&opt.container_runtime);
+        docker::remove_build_volume(&opt);
+        return;
+    }
+
+    if let Some(ref image_uri) = opt.image_uri {
+        deploy_image(image_uri, &opt);
+        return;
+    }
 
-    let cargo_path = PathBuf::from(env::var("CARGO_HOME").expect("Missing CARGO_HOME"));
-    let cargo_registry = {
-        let mut cargo_path = cargo_path.clone();
-        cargo_path.push("registry");
-        cargo_path
+    match opt.builder {
+        Builder::Docker => {
+            docker::check(&opt.container_runtime);
+            // --pull doesn't apply to images built here
+            timing::record_ticking("image", || {
+                match (opt.dockerfile.clone(), opt.docker_image_override.clone()) {
+                    (Some(dockerfile), _) => opt.docker_image = docker::build_image(&dockerfile, &opt),
+                    (None, image) => {
+                        opt.docker_image = image.unwrap_or_else(|| runtime_image(&opt));
+                        docker::prepare_image(&opt);
+                    }
+                }
+            });
+            if opt.sccache {
+                docker::check_sccache(&opt);
+            }
+            if opt.recreate_build_volume {
+                docker::remove_build_volume(&opt);
+                opt.use_build_volume = true;
+            }
+            if opt.use_build_volume {
+                docker::manage_build_volume(&opt);
+            }
+        }
+        _ => local::check(&opt.builder),
+    }
+
+    let crate_dir = match opt.manifest_path {
+        Some(ref manifest_path) => util::project_dir_of(manifest_path),
+        None => env::current_dir().expect("Can't read cwd."),
     };
+    // Workspace members are built from the workspace root, which has the lock file and target dir
+    let project_dir = util::workspace_root(&crate_dir);
+    if opt.package.is_none() && project_dir != crate_dir {
+        opt.package = util::package_name(&crate_dir);
+    }
+
+    if !opt.no_git_metadata {
+        let metadata = util::build_metadata(&project_dir);
+        if opt.description.is_none() {
+            let sha = metadata.iter().find(|(key, _)| *key == "GIT_SHA");
+            opt.description = sha.map(|(_, sha)| sha.clone());
+        }
+        opt.env.extend(metadata.iter().map(|(k, v)| format!("{}={}", k, v)));
+    }
+
+    if opt.offline {
+        util::check_offline(&project_dir);
+    }
+
+    if opt.toolchain.is_none() {
+        opt.toolchain = util::toolchain_channel(&crate_dir).or_else(|| util::toolchain_channel(&project_dir));
+    }
+    if let Some(toolchain) = opt.toolchain.clone() {
+        println!("Building with toolchain {}", toolchain);
+        if let Builder::Docker = opt.builder {
+            docker::check_rustup(&opt);
+        }
+        opt.env.push(format!("RUST_TOOLCHAIN={}", toolchain));
+    }
+
+    let package = opt.package.as_deref();
+    if opt.all_bins || opt.bin.is_empty() {
+        let bins = util::bin_targets(&project_dir, package).unwrap_or_else(|| {
+            eprintln!("Can't find the binaries of the project, list them instead");
+            process::exit(exit::TOOLCHAIN);
+        });
+        if bins.is_empty() {
+            eprintln!("No binaries found in {}", project_dir.display());
+            process::exit(exit::BAD_ARGS);
+        }
+        if !opt.all_bins && bins.len() > 1 {
+            eprintln!("The project has several binaries, pick one (or pass --all-bins):");
+            for bin in &bins {
+                eprintln!("  {}", bin);
+            }
+            process::exit(exit::BAD_ARGS);
+        }
+        opt.bin = bins;
+    } else if !opt.skip_target_check {
+        check_bins(&project_dir, package, &opt.bin);
+    }
+    let multiple = opt.bin.len() > 1;
+    let single_only = opt.signed_object.is_some() || opt.zip_path.is_some() || opt.output_zip.is_some();
+    if multiple && (opt.tail_logs || single_only) {
+        eprintln!("--tail-logs, --signed-object, --zip-path and --output-zip need a single binary");
+        process::exit(exit::BAD_ARGS);
+    }
+    if opt.regions.len() > 1 && (opt.tail_logs || opt.signed_object.is_some()) {
+        eprintln!("--tail-logs and --signed-object need a single region");
+        process::exit(exit::BAD_ARGS);
+    }
+
+    let target = opt.target.clone().unwrap_or_else(|| opt.arch.triple().to_owned());
+    let cross_target = if target == Arch::X86_64.triple() { None } else { Some(target.as_str()) };
+
+    let mut deployments: Vec<Deployment> = opt
+        .bin
+        .iter()
+        .flat_map(|bin| {
+            // With several binaries each goes to its own function in table [arns]
+            let arn = if multiple { bin } else { &opt.arn };
+            let (built_zip_path, zip_path) = zip_paths(&project_dir, cross_target, bin, &opt);
+            util::function_regions(arn, &opt.regions)
+                .into_iter()
+                .map(move |(region, func_name)| Deployment {
+                    bin: bin.clone(),
+                    key: arn.clone(),
+                    region,
+                    func_name,
+                    built_zip_path: built_zip_path.clone(),
+                    zip_path: zip_path.clone(),
+                })
+        })
+        .collect();
+
+    for deployment in &deployments {
+        println!(
+            "Preparing to deploy {} ({}) to {:?} {}",
+            deployment.bin,
+            deployment.zip_path.display(),
+            deployment.region,
+            deployment.func_name
+        );
+        if deployment.zip_path != deployment.built_zip_path {
+            println!("Expecting the built zip at {}", deployment.built_zip_path.display());
+        }
+    }
+
+    if let Builder::Docker = opt.builder {
+        match util::cargo_home() {
+            Some(cargo_home) => println!("Using cargo home {}", cargo_home.display()),
+            None => println!(
+                "Warning: no CARGO_HOME or home directory, dependency caching is disabled and \
+                 dependencies are downloaded on every build"
+            ),
+        }
+    }
+
+    if opt.print_docker_command {
+        if let Builder::Docker = opt.builder {
+            let cargo_home = util::cargo_home();
+            let container = docker::container_name(&opt);
+            let args = docker::build_args(&project_dir, cargo_home.as_deref(), &container, cross_target, &opt);
+            println!("{}", docker::shell_command(&args, &opt));
+            return;
+        }
+        eprintln!("--print-docker-command needs the docker builder");
+        process::exit(exit::BAD_ARGS);
+    }
+
+    // Overwritten when the deploy finishes, so a failed build leaves a record too
+    if let Some(ref report_file) = opt.report_file {
+        write_report_file(report_file, &deployments, None, &opt);
+    }
+
+    // The state of the last successful deploy's build, to skip building when nothing changed
+    let mut build_state = None;
+    let mut built_now = false;
+    if opt.signed_object.is_none() {
+        let state = util::build_state(&project_dir, &target, &opt);
+        let state_path = util::target_dir(&project_dir)
+            .join("lambda")
+            .join(format!("{}.state", opt.bin.join("-")));
+        let zips_exist = deployments.iter().all(|deployment| deployment.zip_path.exists());
+        let previous = fs::read_to_string(&state_path).ok();
+        if !opt.force_build && zips_exist && previous.as_ref() == Some(&state) {
+            println!("Nothing changed since the last deploy, skipping the build (--force-build builds)");
+            opt.skip_unchanged = true;
+        } else {
+            timing::record_ticking("build", || {
+                build(&project_dir, &target, cross_target, &deployments, &opt)
+            });
+            built_now = true;
+            let mut built: Vec<&Path> = Vec::new();
+            for deployment in &deployments {
+                if !built.contains(&deployment.zip_path.as_path()) {
+                    built.push(&deployment.zip_path);
+                    package::ensure_bootstrap(&deployment.zip_path);
+                    if opt.strip {
+                        strip::strip_zip(&deployment.zip_path, &target, &opt);
+                    }
+                    assets::add(&deployment.zip_path, &project_dir);
+                    if !opt.no_normalize_zip {
+                        package::normalize(&deployment.zip_path);
+                    }
+                    package::write_checksum(&deployment.zip_path);
+                }
+            }
+        }
+        build_state = Some((state_path, state));
+    }
+    let mut sized_zips: Vec<PathBuf> = Vec::new();
+    if opt.signed_object.is_none() {
+        for deployment in &deployments {
+            if !sized_zips.contains(&deployment.zip_path) {
+                sized_zips.push(deployment.zip_path.clone());
+                size::report(&deployment.zip_path, &opt);
+            }
+        }
+    }
+    if let Some(ref artifact_dir) = opt.artifact_dir {
+        // Stored once per zip, the regions of a binary deploy the same artifact
+        let mut artifacts: HashMap<PathBuf, PathBuf> = HashMap::new();
+        for deployment in &mut deployments {
+            let (zip_path, bin) = (&deployment.zip_path, &deployment.bin);
+            let artifact = artifacts
+                .entry(zip_path.clone())
+                .or_insert_with(|| match artifacts::latest(artifact_dir, bin) {
+                    Some(latest) if !built_now => latest,
+                    _ => artifacts::stamp(zip_path, bin, artifact_dir, &project_dir, opt.keep_artifacts),
+                })
+                .clone();
+            deployment.zip_path = artifact;
+        }
+    }
+    if let Some(ref output_zip) = opt.output_zip {
+        // A stable line for CI to pick up the exact artifact that's deployed
+        let output_zip = fs::canonicalize(output_zip).unwrap_or_else(|_| output_zip.clone());
+        println!("OUTPUT_ZIP={}", output_zip.display());
+    }
+
+    let results: Vec<Result<FunctionConfiguration, i32>> =
+        deployments.iter().map(|deployment| deploy_bin(deployment, &opt)).collect();
+    let mut hooks_passed = true;
+    for (deployment, result) in deployments.iter().zip(&results) {
+        if let Ok(res) = result {
+            hooks_passed &= hooks::post_deploy(&project_dir, &deployment.key, res);
+        }
+    }
+
+    if deployments.len() > 1 {
+        println!("\n===== Summary =====");
+        for (deployment, result) in deployments.iter().zip(&results) {
+            let function = (&deployment.bin, &deployment.region, &deployment.func_name);
+            match result {
+                Ok(res) => println!(
+                    "{:<20} {:<15} {:<30} {:<8} {}",
+                    function.0,
+                    function.1,
+                    function.2,
+                    disp(res.version.as_ref()),
+                    disp(res.code_sha_256.as_ref())
+                ),
+                Err(_) => println!("{:<20} {:<15} {:<30} FAILED", function.0, function.1, function.2),
+            }
+        }
+    }
+    timing::print_table();
+    if let Some(ref report_file) = opt.report_file {
+        write_report_file(report_file, &deployments, Some(&results), &opt);
+    }
+    if let Some(code) = results.iter().filter_map(|result| result.as_ref().err()).next() {
+        process::exit(*code);
+    }
+    if let Some((state_path, state)) = build_state {
+        if let Err(e) = fs::write(&state_path, state) {
+            eprintln!("Warning: can't write {}: {}", state_path.display(), e);
+        }
+    }
+    if !opt.dry_run {
+        for zip_path in sized_zips {
+            size::record(&zip_path);
+        }
+    }
+    if !hooks_passed {
+        process::exit(1);
+    }
 
-    let args = docker::build_args(project_dir.as_path(), cargo_registry.as_path(), &opt);
+    if opt.tail_logs {
+        let deployment = &deployments[0];
+        println!("\n===== Tailing logs =====");
+        if let Err(e) = logs::tail(&opt.aws, &deployment.region, &deployment.func_name, &opt.tail) {
+            eprintln!("Failed to tail logs:\n{:?}", e);
+            ::std::process::exit(exit::for_rusoto_error(&e));
+        }
+    }
+}
+
+/// Deploys --image-uri to the function in each region, skipping the build
+fn deploy_image(image_uri: &str, opt: &Opt) {
+    let deployments: Vec<Deployment> = util::function_regions(&opt.arn, &opt.regions)
+        .into_iter()
+        .map(|(region, func_name)| Deployment {
+            bin: image_uri.to_owned(),
+            key: opt.arn.clone(),
+            region,
+            func_name,
+            built_zip_path: PathBuf::new(),
+            zip_path: PathBuf::new(),
+        })
+        .collect();
+    for deployment in &deployments {
+        println!(
+            "Preparing to deploy image {} to {:?} {}",
+            image_uri, deployment.region, deployment.func_name
+        );
+    }
+
+    let results: Vec<Result<FunctionConfiguration, i32>> =
+        deployments.iter().map(|deployment| deploy_bin(deployment, opt)).collect();
+    if let Some(ref report_file) = opt.report_file {
+        write_report_file(report_file, &deployments, Some(&results), opt);
+    }
+    if let Some(code) = results.iter().filter_map(|result| result.as_ref().err()).next() {
+        process::exit(*code);
+    }
+}
 
-    println!("Running docker with args {}", args.join(" "));
+/// Prints the name, runtime and last modified time of each function in the deploy regions
+fn list_functions(opt: &Opt) {
+    let mut regions: Vec<String> = util::function_regions(&opt.arn, &opt.regions)
+        .into_iter()
+        .map(|(region, _)| region)
+        .collect();
+    // Listed once each in the given order, e.g. for --regions a,b,a
+    let mut seen = HashSet::new();
+    regions.retain(|region| seen.insert(region.clone()));
+    for region in regions {
+        let client = lambda::create_client(&opt.aws, &region);
+        let mut functions = lambda::list_functions(&client).unwrap_or_else(|e| {
+            eprintln!("\n===== Listing functions in {} FAILED =====", region);
+            eprintln!("{:#?}", e);
+            process::exit(exit::for_rusoto_error(&e));
+        });
+        functions.sort_by(|a, b| a.function_name.cmp(&b.function_name));
 
-    let success = Command::new("docker")
-        .args(args)
-        .env("BIN", &opt.bin)
-        .status_bool();
+        println!("===== Functions in {} =====", region);
+        for function in &functions {
+            let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_owned());
+            println!(
+                "{:<40} {:<16} {}",
+                field(&function.function_name),
+                field(&function.runtime),
+                field(&function.last_modified)
+            );
+        }
+        println!("{} functions", functions.len());
+    }
+}
+
+/// The build image for the function's runtime (--runtime or the current one) from table [images] in
+/// Lambda.toml or the built-in mapping, the default image when the runtime can't be found out
+fn runtime_image(opt: &Opt) -> String {
+    let runtime = opt.runtime.clone().or_else(|| {
+        let (region, func_name) = util::try_function_region(&opt.arn, &opt.regions)?;
+        let client = lambda::create_client(&opt.aws, &region);
+        lambda::get_configuration(&client, &func_name).ok()?.runtime
+    });
+    let runtime = match runtime {
+        Some(runtime) => runtime,
+        None => {
+            println!(
+                "Can't find out the function's runtime, using the default image {}",
+                docker::DEFAULT_IMAGE
+            );
+            return docker::DEFAULT_IMAGE.to_owned();
+        }
+    };
+
+    if let Some(image) = util::lambda_toml().images.get(&runtime).cloned() {
+        println!(
+            "Using image {} for runtime {} from table [images] in {}",
+            image,
+            runtime,
+            util::lambda_toml_name()
+        );
+        return image;
+    }
+    match docker::RUNTIME_IMAGES.iter().find(|(name, _)| *name == runtime) {
+        Some((_, image)) => {
+            println!("Using image {} for runtime {}", image, runtime);
+            (*image).to_owned()
+        }
+        None => {
+            println!(
+                "No image known for runtime {}, using the default image {} (map it in table [images] \
+                 in Lambda.toml)",
+                runtime,
+                docker::DEFAULT_IMAGE
+            );
+            docker::DEFAULT_IMAGE.to_owned()
+        }
+    }
+}
+
+/// Fails before building when a binary isn't a bin target of the project, suggesting the closest
+/// one. Skipped when the bin targets can't be found out.
+fn check_bins(project_dir: &Path, package: Option<&str>, bins: &[String]) {
+    let available = match util::bin_targets(project_dir, package) {
+        Some(available) => available,
+        None => return,
+    };
+    for bin in bins {
+        if available.contains(bin) {
+            continue;
+        }
+        eprintln!("No bin target named {} in {}", bin, project_dir.display());
+        if let Some(closest) = available.iter().min_by_key(|name| util::levenshtein(bin, name)) {
+            eprintln!("Did you mean {}?", closest);
+        }
+        eprintln!("Available bin targets: {}", available.join(", "));
+        eprintln!("Pass --skip-target-check if the image builds it some other way");
+        process::exit(exit::BAD_ARGS);
+    }
+}
 
-    if !success {
-        eprintln!("Running docker failed, check output above");
+/// Runs the pre_build hooks, checks and tests and builds the zips
+fn build(
+    project_dir: &Path,
+    target: &str,
+    cross_target: Option<&str>,
+    deployments: &[Deployment],
+    opt: &Opt,
+) {
+    hooks::pre_build(project_dir, &opt.arn);
+    checks::run(project_dir, opt);
+    if opt.precheck && !local::precheck(project_dir, target, opt) {
+        eprintln!("cargo check failed, not building");
         process::exit(1);
     }
+    if opt.test {
+        let passed = match opt.builder {
+            Builder::Docker => test_with_docker(project_dir, cross_target, opt),
+            _ => local::test(project_dir, target, opt),
+        };
+        if !passed {
+            eprintln!("Tests failed, not deploying");
+            process::exit(1);
+        }
+    }
 
-    let zip_data = {
-        let mut zip_file = File::open(zip_path).expect("Can't open zip path");
-        let mut data = Vec::new();
-        zip_file.read_to_end(&mut data).unwrap();
-        bytes::Bytes::from(data)
+    let mut built_zip_paths: Vec<PathBuf> =
+        deployments.iter().map(|d| d.built_zip_path.clone()).collect();
+    // A binary deployed to several regions is built once
+    built_zip_paths.dedup();
+    match opt.builder {
+        Builder::Docker => build_with_docker(project_dir, &built_zip_paths, cross_target, opt),
+        _ => local::build(project_dir, target, &built_zip_paths, opt),
+    }
+    for zip_path in &built_zip_paths {
+        if !zip_path.is_file() {
+            eprintln!("The build didn't produce {}, check the output above", zip_path.display());
+            process::exit(exit::TOOLCHAIN);
+        }
+    }
+
+    for deployment in deployments {
+        let (built_zip_path, zip_path) = (&deployment.built_zip_path, &deployment.zip_path);
+        if zip_path == built_zip_path {
+            continue;
+        }
+        let dir = zip_path.parent().unwrap_or_else(|| Path::new("."));
+        if let Err(e) = fs::create_dir_all(dir).and_then(|_| fs::copy(built_zip_path, zip_path)) {
+            // The destination comes from --zip-dir or --output-zip
+            eprintln!(
+                "Can't copy {} to {}: {}",
+                built_zip_path.display(),
+                zip_path.display(),
+                e
+            );
+            process::exit(exit::BAD_ARGS);
+        }
+    }
+}
+
+/// Writes the --report-file record of each deployment, failed when there are no `results` yet
+fn write_report_file(
+    path: &Path,
+    deployments: &[Deployment],
+    results: Option<&[Result<FunctionConfiguration, i32>]>,
+    opt: &Opt,
+) {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let git_sha = opt.env.iter().find_map(|env| env.strip_prefix("GIT_SHA="));
+    let image = match opt.builder {
+        Builder::Docker => Some(json!({
+            "name": opt.docker_image,
+            "id": docker::image_id(opt),
+        })),
+        _ => None,
     };
 
-    let client = lambda::create_client(&opt, &region);
-    let req = UpdateFunctionCodeRequest {
+    let records: Vec<serde_json::Value> = deployments
+        .iter()
+        .enumerate()
+        .map(|(i, deployment)| {
+            let mut record = json!({
+                "bin": deployment.bin,
+                "function": deployment.func_name,
+                "region": deployment.region,
+                "zip": deployment.zip_path,
+            });
+            let fields = match results.map(|results| &results[i]) {
+                Some(Ok(res)) => json!({
+                    "success": true,
+                    "function_arn": res.function_arn,
+                    "version": res.version,
+                    "code_sha_256": res.code_sha_256,
+                    "local_code_sha_256": fs::read(&deployment.zip_path)
+                        .ok()
+                        .map(|zip_data| util::code_sha_256(&zip_data)),
+                    "memory_size": res.memory_size,
+                    "timeout": res.timeout,
+                }),
+                Some(Err(code)) => json!({
+                    "success": false,
+                    "error": format!("Deploy failed with exit code {}, see its output", code),
+                }),
+                None => json!({
+                    "success": false,
+                    "error": "The deploy didn't finish, see its output",
+                }),
+            };
+            for (key, value) in fields.as_object().unwrap() {
+                record[key] = value.clone();
+            }
+            record
+        })
+        .collect();
+
+    let report = json!({
+        "timestamp": timestamp,
+        "success": records.iter().all(|record| record["success"] == true),
+        "git_sha": git_sha,
+        "description": opt.description,
+        "image": image,
+        "dry_run": opt.dry_run,
+        "deployments": records,
+        "timing": timing::phases().into_iter().collect::<HashMap<_, _>>(),
+    });
+    if let Err(e) = fs::write(path, serde_json::to_string_pretty(&report).unwrap()) {
+        eprintln!("Warning: can't write the report file {}: {}", path.display(), e);
+    }
+}
+
+/// A binary and the function it's deployed to
+struct Deployment {
+    bin: String,
+    /// FUNCTION_ARN or, with several binaries, the binary's name in [arns]
+    key: String,
+    region: String,
+    func_name: String,
+    /// Where the build leaves the zip
+    built_zip_path: PathBuf,
+    /// Where the zip is deployed from, differs from the above with --zip-dir, --output-zip or
+    /// --artifact-dir
+    zip_path: PathBuf,
+}
+
+fn disp<D: Display>(x: Option<D>) -> String {
+    x.map(|x| format!("{}", x)).unwrap_or("N/A".to_owned())
+}
+
+/// Paths of the zip the build produces for `bin` and of the zip deployed
+fn zip_paths(project_dir: &Path, cross_target: Option<&str>, bin: &str, opt: &Opt) -> (PathBuf, PathBuf) {
+    let zip_file = format!("{}.zip", bin);
+    let built_zip_path = match opt.zip_path {
+        Some(ref zip_path) => zip_path.clone(),
+        None => {
+            let mut zip_path = util::target_dir(project_dir);
+            zip_path.push("lambda");
+            if let Some(target) = cross_target {
+                zip_path.push(target);
+            }
+            zip_path.extend(&[util::profile_dir(&opt.profile_name), &zip_file]);
+            zip_path
+        }
+    };
+    let zip_path = match (&opt.output_zip, &opt.zip_dir) {
+        (Some(output_zip), _) => output_zip.clone(),
+        (None, Some(zip_dir)) => zip_dir.join(&zip_file),
+        (None, None) => built_zip_path.clone(),
+    };
+    (built_zip_path, zip_path)
+}
+
+/// Deploys the built (or signed) zip of one binary and applies the configuration changes, printing
+/// the outcome and returning the exit code on failure
+fn deploy_bin(deployment: &Deployment, opt: &Opt) -> Result<FunctionConfiguration, i32> {
+    let (region, func_name) = (&deployment.region, &deployment.func_name);
+    let client = lambda::create_client(&opt.aws, region);
+    let publish = !opt.dry_run && !opt.no_publish;
+    let mut req = UpdateFunctionCodeRequest {
         dry_run: Some(opt.dry_run),
         function_name: func_name.to_owned(),
-        publish: Some(!opt.dry_run),
-        zip_file: Some(zip_data),
+        // UpdateFunctionCode can't describe the version, so it's then published separately
+        publish: Some(publish && opt.description.is_none()),
         ..Default::default()
     };
-    let res = client.update_function_code(req).sync();
-    if let Ok(res) = res {
-        fn disp<D: Display>(x: Option<D>) -> String {
-            x.map(|x| format!("{}", x)).unwrap_or("N/A".to_owned())
-        }
-        println!("\n===== Deploy successful =====");
-        println!("Function:      {}", disp(res.function_name.as_ref()));
-        println!("Handler        {}", disp(res.handler));
-        println!("Version:       {}", disp(res.version));
-        println!("SHA-256:       {}", disp(res.code_sha_256));
-        println!("Last Modified: {}", disp(res.last_modified));
-        println!("Runtime:       {}", disp(res.runtime));
-        println!("Mem limit:     {} MB", disp(res.memory_size));
-        println!("Time limit:    {} s", disp(res.timeout));
-        println!("ARN:           {}", disp(res.function_arn));
-        println!("Role:          {}", disp(res.role));
-
-        if opt.tail_logs {
-            println!("\n===== Tailing logs =====");
-            let logs_client = logs::create_client(&opt, &region);
-            let func_name = res.function_name.unwrap_or("".into());
-            if let Err(e) = logs::tail(&logs_client, &func_name) {
-                eprintln!("Failed to tail logs:\n{:?}", e);
-                ::std::process::exit(1);
+
+    let mut unchanged = None;
+    // SHA-256 of the zip as uploaded, which Lambda's CodeSha256 must match
+    let mut local_sha = None;
+    if opt.image_uri.is_some() {
+        // Deployed by update_image instead
+    } else if let Some(ref signed_object) = opt.signed_object {
+        let (bucket, key) = util::parse_s3_url(signed_object);
+        req.s3_bucket = Some(bucket);
+        req.s3_key = Some(key);
+    } else {
+        let zip_data = timing::record("zip read", || {
+            let mut zip_file = File::open(&deployment.zip_path).expect("Can't open zip path");
+            let mut data = Vec::new();
+            zip_file.read_to_end(&mut data).unwrap();
+            bytes::Bytes::from(data)
+        });
+
+        if opt.skip_unchanged {
+            unchanged = unchanged_function(&client, func_name, &zip_data)?;
+        }
+        if unchanged.is_none() {
+            if let Some(ref profile) = opt.signing_profile {
+                let (bucket, key) = signing::sign(opt, region, profile, &deployment.bin, zip_data);
+                req.s3_bucket = Some(bucket);
+                req.s3_key = Some(key);
+            } else {
+                local_sha = Some(util::code_sha_256(&zip_data));
+                req.zip_file = Some(zip_data);
+            }
+        }
+    }
+
+    let res = match (unchanged, &opt.image_uri) {
+        (_, Some(image_uri)) => timing::record_ticking("upload", || {
+            update_image(&client, image_uri, deployment, publish, opt)
+        })?,
+        (Some(current), None) => {
+            println!(
+                "{} already runs this code (SHA-256 {}), skipping the upload",
+                func_name,
+                disp(current.code_sha_256.as_ref())
+            );
+            current
+        }
+        (None, None) => {
+            timing::record_ticking("upload", || update_code(&client, req, deployment, publish, opt))?
+        }
+    };
+    if let Some(ref local_sha) = local_sha {
+        if !opt.dry_run && res.code_sha_256.as_ref() != Some(local_sha) {
+            eprintln!("\n===== SHA-256 MISMATCH for {} =====", func_name);
+            eprintln!("Local zip:  {}", local_sha);
+            eprintln!("Lambda has: {}", disp(res.code_sha_256.as_ref()));
+            eprintln!("The zip was likely corrupted in transit, deploy again");
+            return Err(exit::AWS_API);
+        }
+    }
+    let config = apply_configuration(&client, func_name, opt)?;
+    let concurrency = apply_concurrency(&client, func_name, opt)?;
+    let provisioned =
+        timing::record("wait", || apply_provisioned_concurrency(region, func_name, &res, opt))?;
+    let function_url = apply_function_url(region, func_name, opt)?;
+    println!("\n===== Deploy successful =====");
+    println!("Function:      {}", disp(res.function_name.as_ref()));
+    let handler = config.as_ref().and_then(|config| config.handler.clone()).or(res.handler.clone());
+    println!("Handler        {}", disp(handler));
+    println!("Version:       {}", disp(res.version.as_ref()));
+    println!("SHA-256:       {}", disp(res.code_sha_256.as_ref()));
+    if let Some(ref local_sha) = local_sha {
+        println!("Local SHA-256: {}", local_sha);
+    }
+    println!("Last Modified: {}", disp(res.last_modified.as_ref()));
+    match config.as_ref().and_then(|config| config.runtime.as_ref()) {
+        Some(runtime) if res.runtime.as_ref() != Some(runtime) => {
+            println!("Runtime:       {} -> {}", disp(res.runtime.as_ref()), runtime)
+        }
+        _ => println!("Runtime:       {}", disp(res.runtime.as_ref())),
+    }
+    println!("Mem limit:     {} MB", disp(res.memory_size));
+    println!("Time limit:    {} s", disp(res.timeout));
+    println!("ARN:           {}", disp(res.function_arn.as_ref()));
+    println!("Role:          {}", disp(res.role.as_ref()));
+    let cargo_args = util::cargo_args(opt);
+    if !cargo_args.is_empty() {
+        println!("Cargo flags:   {}", cargo_args.join(" "));
+    }
+    if let Some(ref toolchain) = opt.toolchain {
+        println!("Toolchain:     {}", toolchain);
+    }
+    if let Some(concurrency) = concurrency {
+        println!("Concurrency:   {}", concurrency);
+    }
+    if let Some(provisioned) = provisioned {
+        println!("Provisioned:   {}", provisioned);
+    }
+    if let Some(mode) = config.as_ref().and_then(|config| config.tracing_config.as_ref()) {
+        println!("Tracing:       {}", disp(mode.mode.as_ref()));
+    }
+    if let Some(dlq) = config.as_ref().and_then(|config| config.dead_letter_config.as_ref()) {
+        match dlq.target_arn.as_ref().filter(|arn| !arn.is_empty()) {
+            Some(arn) => println!("DLQ:           {}", arn),
+            None => println!("DLQ:           none"),
+        }
+    }
+    if let Some(vpc) = config.as_ref().and_then(|config| config.vpc_config.as_ref()) {
+        println!("VPC:           {}", disp(vpc.vpc_id.as_ref()));
+        println!("Subnets:       {}", vpc.subnet_ids.as_ref().map(|ids| ids.join(", ")).unwrap_or_default());
+        println!(
+            "Sec. groups:   {}",
+            vpc.security_group_ids.as_ref().map(|ids| ids.join(", ")).unwrap_or_default()
+        );
+    }
+    let (console_url, logs_url) = util::console_urls(
+        res.function_arn.as_deref().unwrap_or(""),
+        region,
+        func_name,
+    );
+    if let Some(url) = function_url {
+        println!("Function URL:  {}", url);
+    }
+    println!("Console:       {}", console_url);
+    println!("Logs:          {}", logs_url);
+    if let Some(ref payload) = opt.invoke {
+        smoke_test(&client, func_name, &res, payload, opt)?;
+    }
+    Ok(res)
+}
+
+/// Invokes the deployed version with the --invoke payload, printing the response and log tail
+fn smoke_test(
+    client: &LambdaClient,
+    func_name: &str,
+    res: &FunctionConfiguration,
+    payload: &str,
+    opt: &Opt,
+) -> Result<(), i32> {
+    if opt.dry_run {
+        println!("Dry-run, not invoking {}", func_name);
+        return Ok(());
+    }
+    let qualifier = res.version.clone().filter(|version| version != "$LATEST");
+    println!("\n===== Invoking {}:{} =====", func_name, qualifier.as_deref().unwrap_or("$LATEST"));
+    let response = match lambda::invoke(client, func_name, qualifier, payload) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("\n===== Invoking {} FAILED =====", func_name);
+            eprintln!("{:#?}", e);
+            return Err(exit::for_rusoto_error(&e));
+        }
+    };
+
+    let log = response
+        .log_result
+        .and_then(|log| base64::decode(&log).ok())
+        .map(|log| String::from_utf8_lossy(&log).into_owned());
+    if let Some(log) = log {
+        println!("{}", log.trim_end());
+    }
+    let body = response
+        .payload
+        .map(|payload| String::from_utf8_lossy(&payload).into_owned())
+        .unwrap_or_default();
+    println!("Status:        {}", disp(response.status_code));
+    println!("Response:      {}", body);
+    match response.function_error {
+        Some(error) => {
+            eprintln!("\n===== Invoking {} returned a function error ({}) =====", func_name, error);
+            Err(exit::FUNCTION_ERROR)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Points the function at --image-uri and publishes it with --description when publishing
+fn update_image(
+    client: &LambdaClient,
+    image_uri: &str,
+    deployment: &Deployment,
+    publish: bool,
+    opt: &Opt,
+) -> Result<FunctionConfiguration, i32> {
+    let func_name = &deployment.func_name;
+    let publish_now = publish && opt.description.is_none();
+    let res = lambda::update_function_image(
+        &opt.aws,
+        &deployment.region,
+        func_name,
+        image_uri,
+        publish_now,
+        opt.dry_run,
+    );
+    let res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("\n===== Deploy of image {} FAILED =====", image_uri);
+            eprintln!("{}", e);
+            return Err(exit::AWS_API);
+        }
+    };
+    match opt.description {
+        Some(ref description) if publish => {
+            match lambda::publish_version(client, func_name, res.code_sha_256.clone(), description) {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    eprintln!("\n===== Publishing {} FAILED =====", func_name);
+                    eprintln!("{:#?}", e);
+                    Err(exit::for_rusoto_error(&e))
+                }
+            }
+        }
+        _ => Ok(res),
+    }
+}
+
+/// Uploads the code and publishes it with --description when publishing
+fn update_code(
+    client: &LambdaClient,
+    req: UpdateFunctionCodeRequest,
+    deployment: &Deployment,
+    publish: bool,
+    opt: &Opt,
+) -> Result<FunctionConfiguration, i32> {
+    let res = match client.update_function_code(req).sync() {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("\n===== Deploy of {} FAILED =====", deployment.bin);
+            eprintln!("{:#?}", e);
+            return Err(exit::for_rusoto_error(&e));
+        }
+    };
+    match opt.description {
+        Some(ref description) if publish => {
+            let func_name = &deployment.func_name;
+            match lambda::publish_version(client, func_name, res.code_sha_256.clone(), description) {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    eprintln!("\n===== Publishing {} FAILED =====", deployment.bin);
+                    eprintln!("{:#?}", e);
+                    Err(exit::for_rusoto_error(&e))
+                }
             }
         }
+        _ => Ok(res),
+    }
+}
+
+/// The function's configuration when its code has the same SHA-256 as the zip
+fn unchanged_function(
+    client: &LambdaClient,
+    func_name: &str,
+    zip_data: &[u8],
+) -> Result<Option<FunctionConfiguration>, i32> {
+    let current = match lambda::get_configuration(client, func_name) {
+        Ok(current) => current,
+        Err(e) => {
+            eprintln!("\n===== Reading the function's configuration FAILED =====");
+            eprintln!("{:#?}", e);
+            return Err(exit::for_rusoto_error(&e));
+        }
+    };
+    if current.code_sha_256.as_ref() == Some(&util::code_sha_256(zip_data)) {
+        Ok(Some(current))
     } else {
-        eprintln!("\n===== Deploy FAILED =====");
-        eprintln!("{:#?}", res);
-        ::std::process::exit(1);
+        Ok(None)
+    }
+}
+
+/// Applies configuration changes like --subnet, returning the updated configuration
+fn apply_configuration(
+    client: &LambdaClient,
+    func_name: &str,
+    opt: &Opt,
+) -> Result<Option<FunctionConfiguration>, i32> {
+    let environment = if opt.lambda_env.is_empty()
+        && opt.lambda_env_file.is_none()
+        && opt.lambda_env_secret_values.is_empty()
+    {
+        None
+    } else {
+        Some(merge_environment(client, func_name, opt)?)
+    };
+    let req = match lambda::configuration_update(func_name, opt, environment) {
+        Some(req) => req,
+        None => return Ok(None),
+    };
+    if opt.dry_run {
+        let mut shown = req.clone();
+        let variables = shown.environment.as_mut().and_then(|e| e.variables.as_mut());
+        for (key, value) in variables.into_iter().flatten() {
+            if opt.lambda_env_secret_values.contains_key(key) {
+                *value = "****".to_owned();
+            }
+        }
+        println!("Dry-run, not applying configuration changes:\n{:#?}", shown);
+        return Ok(None);
+    }
+
+    match lambda::update_configuration(client, req) {
+        Ok(config) => Ok(Some(config)),
+        Err(e) => {
+            eprintln!("\n===== Updating configuration FAILED =====");
+            eprintln!("{:#?}", e);
+            Err(exit::for_rusoto_error(&e))
+        }
+    }
+}
+
+/// Merges --lambda-env-file, --lambda-env and --lambda-env-secret into the function's current
+/// environment variables, showing the changes and asking for confirmation on a terminal unless
+/// --yes is given
+fn merge_environment(
+    client: &LambdaClient,
+    func_name: &str,
+    opt: &Opt,
+) -> Result<HashMap<String, String>, i32> {
+    let current = match lambda::get_environment(client, func_name) {
+        Ok(current) => current,
+        Err(e) => {
+            eprintln!("\n===== Reading the function's environment FAILED =====");
+            eprintln!("{:#?}", e);
+            return Err(exit::for_rusoto_error(&e));
+        }
+    };
+    let mut merged = current.clone();
+    if let Some(ref path) = opt.lambda_env_file {
+        merged.extend(util::read_dotenv_file(path));
+    }
+    for env in &opt.lambda_env {
+        let mut parts = env.splitn(2, '=');
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            merged.insert(key.to_owned(), value.to_owned());
+        }
+    }
+    merged.extend(opt.lambda_env_secret_values.clone());
+
+    let secret_keys: Vec<&str> = opt.lambda_env_secret_values.keys().map(String::as_str).collect();
+    let diff = util::env_diff(&current, &merged, &secret_keys);
+    if diff.is_empty() {
+        println!("Environment of {} is unchanged", func_name);
+        return Ok(merged);
+    }
+    println!("Environment changes for {}:", func_name);
+    for line in &diff {
+        println!("  {}", line);
+    }
+    if !opt.dry_run && !opt.yes && io::stdin().is_terminal() && !util::confirm("Apply?") {
+        eprintln!("Aborted");
+        return Err(1);
+    }
+    Ok(merged)
+}
+
+/// Applies --reserved-concurrency or --no-reserved-concurrency, returning the setting to report
+fn apply_concurrency(client: &LambdaClient, func_name: &str, opt: &Opt) -> Result<Option<String>, i32> {
+    if let Some(limit) = opt.reserved_concurrency {
+        if opt.dry_run {
+            return Ok(Some(format!("{} reserved (dry-run, not applied)", limit)));
+        }
+        match lambda::put_concurrency(client, func_name, limit) {
+            Ok(applied) => Ok(Some(format!("{} reserved", applied))),
+            Err(e) => {
+                eprintln!("\n===== Setting reserved concurrency FAILED =====");
+                eprintln!("{:#?}", e);
+                Err(exit::for_rusoto_error(&e))
+            }
+        }
+    } else if opt.no_reserved_concurrency {
+        if opt.dry_run {
+            return Ok(Some("unreserved (dry-run, not applied)".to_owned()));
+        }
+        match lambda::delete_concurrency(client, func_name) {
+            Ok(()) => Ok(Some("unreserved".to_owned())),
+            Err(e) => {
+                eprintln!("\n===== Removing reserved concurrency FAILED =====");
+                eprintln!("{:#?}", e);
+                Err(exit::for_rusoto_error(&e))
+            }
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Creates or updates the function URL for --function-url, returning the URL to report
+fn apply_function_url(region: &str, func_name: &str, opt: &Opt) -> Result<Option<String>, i32> {
+    let auth_type = match opt.function_url {
+        Some(ref auth_type) => auth_type,
+        None => return Ok(None),
+    };
+    if opt.dry_run {
+        return Ok(Some(format!("{} auth (dry-run, not applied)", auth_type)));
+    }
+
+    let cors_given = !opt.cors_allow_origins.is_empty()
+        || !opt.cors_allow_methods.is_empty()
+        || !opt.cors_allow_headers.is_empty()
+        || opt.cors_allow_credentials
+        || opt.cors_max_age.is_some();
+    let cors = if cors_given {
+        Some(json!({
+            "AllowOrigins": opt.cors_allow_origins,
+            "AllowMethods": opt.cors_allow_methods,
+            "AllowHeaders": opt.cors_allow_headers,
+            "AllowCredentials": opt.cors_allow_credentials,
+            "MaxAge": opt.cors_max_age.unwrap_or(0),
+        }))
+    } else {
+        None
+    };
+    let result = lambda::put_function_url(&opt.aws, region, func_name, auth_type, cors).and_then(|url| {
+        if auth_type == "NONE" {
+            lambda::allow_public_url(&opt.aws, region, func_name)?;
+        }
+        Ok(url)
+    });
+    match result {
+        Ok(url) => Ok(Some(url)),
+        Err(e) => {
+            eprintln!("\n===== Setting the function URL FAILED =====");
+            eprintln!("{}", e);
+            Err(exit::AWS_API)
+        }
+    }
+}
+
+/// Applies --provisioned-concurrency to the published version, waiting for it with --wait and
+/// returning the status to report
+fn apply_provisioned_concurrency(
+    region: &str,
+    func_name: &str,
+    res: &FunctionConfiguration,
+    opt: &Opt,
+) -> Result<Option<String>, i32> {
+    let executions = match opt.provisioned_concurrency {
+        Some(executions) => executions,
+        None => return Ok(None),
+    };
+    // A skipped upload reports $LATEST, which can't have provisioned concurrency
+    let version = match res.version.as_ref().filter(|version| version.as_str() != "$LATEST") {
+        Some(version) => version,
+        None => {
+            eprintln!("\n===== Provisioning concurrency FAILED =====");
+            eprintln!("No version was published to provision concurrency for");
+            return Err(1);
+        }
+    };
+
+    let mut status = match lambda::put_provisioned_concurrency(&opt.aws, region, func_name, version, executions) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("\n===== Provisioning concurrency FAILED =====");
+            eprintln!("{}", e);
+            return Err(exit::AWS_API);
+        }
+    };
+    if opt.wait {
+        println!("Waiting for provisioned concurrency of version {} to be ready", version);
+    }
+    let deadline = Instant::now() + opt.wait_timeout;
+    while opt.wait && status.as_deref() == Some("IN_PROGRESS") {
+        if Instant::now() >= deadline {
+            eprintln!("\n===== Provisioning concurrency TIMED OUT =====");
+            eprintln!(
+                "Version {} wasn't ready after {} s (--wait-timeout), it's still being provisioned",
+                version,
+                opt.wait_timeout.as_secs()
+            );
+            return Err(exit::TIMEOUT);
+        }
+        thread::sleep(Duration::from_secs(5));
+        match lambda::provisioned_concurrency_status(&opt.aws, region, func_name, version) {
+            Ok((current, reason)) => {
+                if current.as_deref() == Some("FAILED") {
+                    eprintln!("\n===== Provisioning concurrency FAILED =====");
+                    eprintln!("{}", reason.unwrap_or_default());
+                    return Err(exit::AWS_API);
+                }
+                status = current;
+            }
+            Err(e) => {
+                eprintln!("\n===== Reading provisioned concurrency FAILED =====");
+                eprintln!("{}", e);
+                return Err(exit::AWS_API);
+            }
+        }
+    }
+    Ok(Some(format!("{} on version {} ({})", executions, version, disp(status))))
+}
+
+/// Runs the tests in the build image, with the output passed through as is
+fn test_with_docker(project_dir: &Path, target: Option<&str>, opt: &Opt) -> bool {
+    if docker::is_remote_daemon() && !opt.force_mount {
+        println!("Warning: --test isn't supported with remote docker daemons, skipping the tests");
+        return true;
+    }
+    let cargo_home = util::cargo_home();
+    let container = format!("{}-test", docker::container_name(opt));
+    let args = docker::test_args(project_dir, cargo_home.as_deref(), &container, target, opt);
+
+    println!("Running {} with args {}", opt.container_runtime, args.join(" "));
+
+    let success = Command::new(&opt.container_runtime).args(args).status_bool();
+    if docker::should_map_user(opt) {
+        docker::restore_ownership(project_dir, cargo_home.as_deref(), opt);
+    }
+    success
+}
+
+fn build_with_docker(project_dir: &Path, zip_paths: &[PathBuf], target: Option<&str>, opt: &Opt) {
+    let cargo_home = util::cargo_home();
+
+    docker::note_emulation(target, opt);
+    docker::warn_stopped_containers(opt);
+
+    let container = docker::container_name(opt);
+    let result = if docker::is_remote_daemon() && !opt.force_mount {
+        println!("DOCKER_HOST points to a remote daemon, copying the project into the build container");
+        docker::build_with_copy(project_dir, cargo_home.as_deref(), &container, zip_paths, target, opt)
+    } else {
+        docker::warn_large_target(project_dir, opt);
+        docker::check_disk_space(project_dir, opt);
+        let args = docker::build_args(project_dir, cargo_home.as_deref(), &container, target, opt);
+
+        println!("Running {} with args {}", opt.container_runtime, args.join(" "));
+
+        let result = docker::run_container(
+            Command::new(&opt.container_runtime).args(args).envs(docker::bin_env(opt)),
+            &container,
+            project_dir,
+            opt,
+        );
+        if docker::should_map_user(opt) {
+            docker::restore_ownership(project_dir, cargo_home.as_deref(), opt);
+        }
+        result
+    };
+
+    if let Err(code) = result {
+        eprintln!("Running {} failed, check output above", opt.container_runtime);
+        docker::check_disk_space_after_failure(project_dir, opt);
+        if opt.keep_container {
+            docker::print_debug_hints(&container, opt);
+        }
+        process::exit(code);
     }
 }
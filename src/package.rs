@@ -0,0 +1,288 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use sha2::{Digest, Sha256};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+/// Makes sure the zip has a `bootstrap`, which the provided runtimes execute. A single executable
+/// named otherwise (e.g. after the bin target by a custom image) is renamed to bootstrap, several
+/// fail the deploy, and a zip without executables is warned about, so a misnamed binary shows up at
+/// deploy time rather than as a failing function.
+pub(crate) fn ensure_bootstrap(zip_path: &Path) {
+    let entries = match entries(zip_path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Warning: can't read {} to check its contents: {}", zip_path.display(), e);
+            return;
+        }
+    };
+    if entries.iter().any(|(name, _)| name == "bootstrap") {
+        return;
+    }
+
+    match executables(&entries).as_slice() {
+        [executable] => {
+            if let Err(e) = rename_entry(zip_path, executable, "bootstrap") {
+                eprintln!("Can't rename {} to bootstrap in {}: {}", executable, zip_path.display(), e);
+                process::exit(1);
+            }
+            println!(
+                "Note: renamed {} to bootstrap in {}, the provided runtimes execute bootstrap",
+                executable,
+                zip_path.display()
+            );
+        }
+        [] => {
+            let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+            println!(
+                "Warning: {} has no bootstrap, the provided runtimes won't find the entrypoint and \
+                 the function fails to start. The zip has: {}",
+                zip_path.display(),
+                if names.is_empty() { "nothing".to_owned() } else { names.join(", ") }
+            );
+        }
+        executables => {
+            eprintln!(
+                "{} has no bootstrap but several executables ({}), can't tell which one the \
+                 function should run. Name it bootstrap in the build.",
+                zip_path.display(),
+                executables.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", ")
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Names of the executable files among the entries
+fn executables(entries: &[(String, Option<u32>)]) -> Vec<&String> {
+    entries
+        .iter()
+        .filter(|(name, mode)| !name.ends_with('/') && mode.is_some_and(|mode| mode & 0o111 != 0))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Names and unix modes of the entries in the zip
+fn entries(zip_path: &Path) -> zip::result::ZipResult<Vec<(String, Option<u32>)>> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        entries.push((entry.name().to_owned(), entry.unix_mode()));
+    }
+    Ok(entries)
+}
+
+/// Writes the zip again with `from` renamed to `to` and made executable, replacing the zip only
+/// once the copy is complete
+fn rename_entry(zip_path: &Path, from: &str, to: &str) -> zip::result::ZipResult<()> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let tmp_path = zip_path.with_extension("zip.tmp");
+    let mut zip = ZipWriter::new(File::create(&tmp_path)?);
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let (name, mode) = if entry.name() == from {
+            (to.to_owned(), 0o755)
+        } else {
+            (entry.name().to_owned(), entry.unix_mode().unwrap_or(0o644))
+        };
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(mode)
+            .last_modified_time(entry.last_modified());
+        if name.ends_with('/') {
+            zip.add_directory(name, options)?;
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+    zip.finish()?;
+    drop(zip);
+    fs::rename(&tmp_path, zip_path).map_err(|e: io::Error| {
+        fs::remove_file(&tmp_path).ok();
+        e.into()
+    })
+}
+
+/// Writes the zip again with the entries sorted by name, timestamps of 1980-01-01, modes of 0755
+/// for executables and 0644 for the rest, and no extra fields, so the same files always give a
+/// byte-identical zip and the same SHA-256
+pub(crate) fn normalize(zip_path: &Path) {
+    if let Err(e) = rewrite_normalized(zip_path) {
+        eprintln!("Can't normalize {}: {}", zip_path.display(), e);
+        process::exit(1);
+    }
+}
+
+fn rewrite_normalized(zip_path: &Path) -> zip::result::ZipResult<()> {
+    let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let executable = entry.unix_mode().is_some_and(|mode| mode & 0o111 != 0);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        entries.push((entry.name().to_owned(), executable, data));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let tmp_path = zip_path.with_extension("zip.tmp");
+    let mut zip = ZipWriter::new(File::create(&tmp_path)?);
+    for (name, executable, data) in entries {
+        let directory = name.ends_with('/');
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(if executable || directory { 0o755 } else { 0o644 })
+            .last_modified_time(DateTime::default());
+        if directory {
+            zip.add_directory(name, options)?;
+        } else {
+            zip.start_file(name, options)?;
+            zip.write_all(&data)?;
+        }
+    }
+    zip.finish()?;
+    drop(zip);
+    fs::rename(&tmp_path, zip_path).map_err(|e: io::Error| {
+        fs::remove_file(&tmp_path).ok();
+        e.into()
+    })
+}
+
+/// Writes `<zip>.sha256` with the hex SHA-256 in `sha256sum` format, for archiving with the zip
+pub(crate) fn write_checksum(zip_path: &Path) {
+    let data = match fs::read(zip_path) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let name = zip_path.file_name().unwrap_or_default().to_string_lossy();
+    let mut path = zip_path.as_os_str().to_owned();
+    path.push(".sha256");
+    if let Err(e) = fs::write(&path, format!("{:x}  {}\n", Sha256::digest(&data), name)) {
+        eprintln!("Warning: can't write {}: {}", Path::new(&path).display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// Writes a zip of `(name, mode, contents)` entries built in memory to a fresh directory
+    fn fixture(test: &str, entries: &[(&str, u32, &str)]) -> PathBuf {
+        fixture_at(test, entries, DateTime::default())
+    }
+
+    fn fixture_at(test: &str, entries: &[(&str, u32, &str)], modified: DateTime) -> PathBuf {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, mode, contents) in entries {
+            let options =
+                FileOptions::default().unix_permissions(*mode).last_modified_time(modified);
+            if name.ends_with('/') {
+                zip.add_directory(*name, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        let dir = env::temp_dir().join(format!("cargo-aws-lambda-{}-{}", test, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let zip_path = dir.join("func.zip");
+        fs::write(&zip_path, data).unwrap();
+        zip_path
+    }
+
+    /// Names and permission bits of the entries
+    fn contents(zip_path: &Path) -> Vec<(String, u32)> {
+        let entries = entries(zip_path).unwrap();
+        fs::remove_dir_all(zip_path.parent().unwrap()).ok();
+        entries.into_iter().map(|(name, mode)| (name, mode.unwrap_or(0) & 0o777)).collect()
+    }
+
+    #[test]
+    fn renames_lone_executable() {
+        let zip_path = fixture("rename", &[("my-func", 0o755, "ELF"), ("config.json", 0o644, "{}")]);
+        ensure_bootstrap(&zip_path);
+        assert_eq!(
+            contents(&zip_path),
+            vec![("bootstrap".to_owned(), 0o755), ("config.json".to_owned(), 0o644)]
+        );
+    }
+
+    #[test]
+    fn keeps_existing_bootstrap() {
+        let zip_path = fixture("existing", &[("bootstrap", 0o755, "ELF"), ("helper", 0o755, "sh")]);
+        let before = fs::read(&zip_path).unwrap();
+        ensure_bootstrap(&zip_path);
+        assert_eq!(fs::read(&zip_path).unwrap(), before);
+        assert_eq!(
+            contents(&zip_path),
+            vec![("bootstrap".to_owned(), 0o755), ("helper".to_owned(), 0o755)]
+        );
+    }
+
+    #[test]
+    fn finds_executables_among_entries() {
+        let zip_path = fixture(
+            "several",
+            &[
+                ("assets/", 0o755, ""),
+                ("assets/index.html", 0o644, "<html>"),
+                ("api", 0o755, "ELF"),
+                ("worker", 0o700, "ELF"),
+                ("README", 0o644, "docs"),
+            ],
+        );
+        let entries = entries(&zip_path).unwrap();
+        fs::remove_dir_all(zip_path.parent().unwrap()).ok();
+        assert_eq!(executables(&entries), vec!["api", "worker"]);
+    }
+
+    #[test]
+    fn normalize_is_deterministic() {
+        let first = fixture_at(
+            "normalize-a",
+            &[("bootstrap", 0o755, "ELF"), ("static/", 0o700, ""), ("static/a.txt", 0o600, "a")],
+            DateTime::from_date_and_time(2021, 3, 4, 5, 6, 8).unwrap(),
+        );
+        let second = fixture_at(
+            "normalize-b",
+            &[("static/a.txt", 0o664, "a"), ("static/", 0o755, ""), ("bootstrap", 0o700, "ELF")],
+            DateTime::from_date_and_time(2024, 11, 12, 13, 14, 16).unwrap(),
+        );
+        assert_ne!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        normalize(&first);
+        normalize(&second);
+        write_checksum(&first);
+        write_checksum(&second);
+        let data = fs::read(&first).unwrap();
+        let checksum =
+            |zip_path: &Path| fs::read_to_string(zip_path.with_extension("zip.sha256")).unwrap();
+        let (first_checksum, second_checksum) = (checksum(&first), checksum(&second));
+        let same = data == fs::read(&second).unwrap();
+        let second_entries = contents(&second);
+        fs::remove_dir_all(first.parent().unwrap()).ok();
+
+        assert!(same, "normalized zips differ");
+        assert_eq!(first_checksum, format!("{:x}  func.zip\n", Sha256::digest(&data)));
+        assert_eq!(first_checksum, second_checksum);
+        assert_eq!(
+            second_entries,
+            vec![
+                ("bootstrap".to_owned(), 0o755),
+                ("static/".to_owned(), 0o755),
+                ("static/a.txt".to_owned(), 0o644),
+            ]
+        );
+    }
+}
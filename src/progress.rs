@@ -0,0 +1,213 @@
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Condenses cargo's `Compiling ...` lines into a progress counter and highlights errors and
+/// warnings, everything else is passed through
+struct Formatter {
+    compiled: usize,
+    progress_shown: bool,
+    terminal: bool,
+    color: bool,
+    /// Host path of the project mounted at /code in the container
+    project_dir: Option<String>,
+}
+
+impl Formatter {
+    fn line(&mut self, line: &str, stderr: bool) {
+        let rewritten = self.host_paths(line);
+        let line = rewritten.as_str();
+        // Cargo colors its output with CARGO_TERM_COLOR=always, so match on the plain text
+        let plain = strip_ansi(line);
+        let trimmed = plain.trim_start();
+        if let Some(krate) = trimmed.strip_prefix("Compiling ") {
+            self.compiled += 1;
+            if self.terminal {
+                print!("\r\x1b[K   Compiling [{}] {}", self.compiled, krate);
+                io::stdout().flush().ok();
+                self.progress_shown = true;
+            } else {
+                println!("   Compiling [{}] {}", self.compiled, krate);
+            }
+            return;
+        }
+
+        if self.progress_shown {
+            println!();
+            self.progress_shown = false;
+        }
+
+        // JSON diagnostics from --message-format json pass through as is for tools to consume
+        if trimmed.starts_with('{') {
+            println!("{}", line);
+            return;
+        }
+
+        let line = match (self.color && plain.len() == line.len(), trimmed) {
+            (true, l) if l.starts_with("error") => format!("\x1b[1;31m{}\x1b[0m", line),
+            (true, l) if l.starts_with("warning") => format!("\x1b[1;33m{}\x1b[0m", line),
+            _ => line.to_owned(),
+        };
+        if stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+impl Formatter {
+    /// Rewrites /code/ paths to the host project, keeping the line as is when that would break a
+    /// JSON diagnostic (e.g. with backslashes in windows paths)
+    fn host_paths(&self, line: &str) -> String {
+        let project_dir = match self.project_dir {
+            Some(ref project_dir) => project_dir,
+            None => return line.to_owned(),
+        };
+
+        let mut rewritten = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(i) = rest.find("/code/") {
+            // Only at the start of a path, not in the middle of e.g. /root/code/
+            let before = &rest[..i];
+            let starts_path = ends_with_escape(before)
+                || before
+                    .chars()
+                    .last()
+                    .is_none_or(|c| !(c.is_alphanumeric() || "/._-".contains(c)));
+            rewritten.push_str(&rest[..i]);
+            if starts_path {
+                rewritten.push_str(project_dir);
+                rewritten.push('/');
+            } else {
+                rewritten.push_str("/code/");
+            }
+            rest = &rest[i + "/code/".len()..];
+        }
+        rewritten.push_str(rest);
+
+        let json = line.trim_start().starts_with('{');
+        if json && serde_json::from_str::<serde_json::Value>(&rewritten).is_err() {
+            return line.to_owned();
+        }
+        rewritten
+    }
+}
+
+/// Removes ANSI color sequences like `\x1b[1;31m`
+fn strip_ansi(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // The sequence ends at its first letter
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Whether `text` ends with a color sequence, e.g. the reset before a path
+fn ends_with_escape(text: &str) -> bool {
+    match text.rfind("\x1b[") {
+        Some(i) => {
+            let sequence = &text[i + 2..];
+            sequence.ends_with('m')
+                && sequence[..sequence.len() - 1].chars().all(|c| c.is_ascii_digit() || c == ';')
+        }
+        None => false,
+    }
+}
+
+/// Forwards the piped stdout and stderr of the child through the formatter, rewriting paths in
+/// the container to `project_dir` and highlighting errors when `color` is set, join the returned
+/// handles after the child exits to get the stderr lines
+pub(crate) fn forward(
+    child: &mut Child,
+    project_dir: Option<&Path>,
+    color: bool,
+) -> Vec<JoinHandle<Vec<String>>> {
+    let formatter = Arc::new(Mutex::new(Formatter {
+        compiled: 0,
+        progress_shown: false,
+        terminal: io::stdout().is_terminal(),
+        color,
+        project_dir: project_dir.map(|dir| dir.display().to_string()),
+    }));
+
+    let mut handles = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        handles.push(spawn_reader(stdout, false, formatter.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        handles.push(spawn_reader(stderr, true, formatter));
+    }
+    handles
+}
+
+/// Passes the child's stderr through as is for --raw-build-output, returning the lines it wrote
+/// like `forward` so failures can still be told apart
+pub(crate) fn tee_stderr(child: &mut Child) -> Vec<JoinHandle<Vec<String>>> {
+    let mut stderr = match child.stderr.take() {
+        Some(stderr) => stderr,
+        None => return Vec::new(),
+    };
+    let handle = thread::spawn(move || {
+        let mut lines = Vec::new();
+        let mut pending = Vec::new();
+        let mut buf = [0; 8192];
+        // Copied in chunks rather than lines, so prompts and progress bars show up right away
+        while let Ok(read) = stderr.read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+            let mut out = io::stderr();
+            out.write_all(&buf[..read]).ok();
+            out.flush().ok();
+            pending.extend_from_slice(&buf[..read]);
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                lines.push(strip_ansi(String::from_utf8_lossy(&line).trim_end()));
+            }
+        }
+        if !pending.is_empty() {
+            lines.push(strip_ansi(String::from_utf8_lossy(&pending).trim_end()));
+        }
+        lines
+    });
+    vec![handle]
+}
+
+fn spawn_reader<R: Read + Send + 'static>(
+    reader: R,
+    stderr: bool,
+    formatter: Arc<Mutex<Formatter>>,
+) -> JoinHandle<Vec<String>> {
+    thread::spawn(move || {
+        let mut stderr_lines = Vec::new();
+        for line in BufReader::new(reader).lines() {
+            match line {
+                Ok(line) => {
+                    // A container run with -t ends its lines with \r\n
+                    let line = line.trim_end_matches('\r');
+                    formatter.lock().unwrap().line(line, stderr);
+                    if stderr {
+                        stderr_lines.push(strip_ansi(line));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        let mut formatter = formatter.lock().unwrap();
+        if formatter.progress_shown {
+            println!();
+            formatter.progress_shown = false;
+        }
+        stderr_lines
+    })
+}
@@ -0,0 +1,197 @@
+use serde::Serialize;
+use serde_json::json;
+
+/// The START, END and REPORT lines Lambda writes around each invocation
+#[derive(Debug, PartialEq)]
+pub(crate) enum PlatformLine {
+    Start { request_id: String, version: String },
+    End { request_id: String },
+    Report(Report),
+}
+
+/// Metrics of an invocation from its REPORT line
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Report {
+    pub request_id: String,
+    pub duration_ms: f64,
+    pub billed_duration_ms: u64,
+    pub memory_size_mb: u64,
+    pub max_memory_used_mb: u64,
+    /// Only present on cold starts
+    pub init_duration_ms: Option<f64>,
+}
+
+/// Parses a log message as a platform line, None for the function's own output
+pub(crate) fn parse(message: &str) -> Option<PlatformLine> {
+    let message = message.trim_end();
+    if let Some(rest) = message.strip_prefix("START RequestId: ") {
+        let mut parts = rest.splitn(2, " Version: ");
+        let request_id = parts.next()?.trim().to_owned();
+        let version = parts.next().unwrap_or("").trim().to_owned();
+        Some(PlatformLine::Start { request_id, version })
+    } else if let Some(rest) = message.strip_prefix("END RequestId: ") {
+        Some(PlatformLine::End {
+            request_id: rest.trim().to_owned(),
+        })
+    } else if message.starts_with("REPORT RequestId: ") {
+        parse_report(message).map(PlatformLine::Report)
+    } else {
+        None
+    }
+}
+
+/// Fields of a REPORT line are tab separated `Name: value unit` pairs
+fn parse_report(message: &str) -> Option<Report> {
+    let field = |name: &str| {
+        message
+            .split('\t')
+            .map(str::trim)
+            .find_map(|part| part.strip_prefix(name)?.strip_prefix(": "))
+            .and_then(|value| value.split_whitespace().next())
+    };
+    let number = |name: &str| field(name).and_then(|value| value.parse::<f64>().ok());
+
+    Some(Report {
+        request_id: field("REPORT RequestId")?.to_owned(),
+        duration_ms: number("Duration")?,
+        billed_duration_ms: number("Billed Duration")? as u64,
+        memory_size_mb: number("Memory Size")? as u64,
+        max_memory_used_mb: number("Max Memory Used")? as u64,
+        init_duration_ms: number("Init Duration"),
+    })
+}
+
+/// A log event as a JSON line, with the fields of platform lines broken out
+pub(crate) fn event_json(timestamp: i64, message: &str, line: Option<&PlatformLine>) -> String {
+    let event = match line {
+        Some(PlatformLine::Start { request_id, version }) => json!({
+            "timestamp": timestamp,
+            "type": "start",
+            "request_id": request_id,
+            "version": version,
+        }),
+        Some(PlatformLine::End { request_id }) => json!({
+            "timestamp": timestamp,
+            "type": "end",
+            "request_id": request_id,
+        }),
+        Some(PlatformLine::Report(report)) => json!({
+            "timestamp": timestamp,
+            "type": "report",
+            "report": report,
+        }),
+        None => json!({
+            "timestamp": timestamp,
+            "type": "message",
+            "message": message.trim_end_matches('\n'),
+        }),
+    };
+    event.to_string()
+}
+
+/// A REPORT line's metrics on one line of text
+pub(crate) fn report_text(report: &Report) -> String {
+    let init = report
+        .init_duration_ms
+        .map(|init| format!(" init {:.2} ms", init))
+        .unwrap_or_default();
+    format!(
+        "{} duration {:.2} ms billed {} ms memory {}/{} MB{}",
+        report.request_id,
+        report.duration_ms,
+        report.billed_duration_ms,
+        report.max_memory_used_mb,
+        report.memory_size_mb,
+        init
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST_ID: &str = "8f507cfc-4a2e-4f7b-9c3f-0a1b2c3d4e5f";
+
+    #[test]
+    fn parses_start_and_end() {
+        assert_eq!(
+            parse(&format!("START RequestId: {} Version: $LATEST\n", REQUEST_ID)),
+            Some(PlatformLine::Start {
+                request_id: REQUEST_ID.to_owned(),
+                version: "$LATEST".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse(&format!("END RequestId: {}\n", REQUEST_ID)),
+            Some(PlatformLine::End { request_id: REQUEST_ID.to_owned() })
+        );
+    }
+
+    #[test]
+    fn parses_warm_report() {
+        let line = format!(
+            "REPORT RequestId: {}\tDuration: 1.52 ms\tBilled Duration: 2 ms\tMemory Size: 128 MB\t\
+             Max Memory Used: 20 MB\t\n",
+            REQUEST_ID
+        );
+        assert_eq!(
+            parse(&line),
+            Some(PlatformLine::Report(Report {
+                request_id: REQUEST_ID.to_owned(),
+                duration_ms: 1.52,
+                billed_duration_ms: 2,
+                memory_size_mb: 128,
+                max_memory_used_mb: 20,
+                init_duration_ms: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_cold_start_report() {
+        let line = format!(
+            "REPORT RequestId: {}\tDuration: 45.08 ms\tBilled Duration: 77 ms\tMemory Size: 1024 MB\t\
+             Max Memory Used: 31 MB\tInit Duration: 31.27 ms\t\nXRAY TraceId: 1-5e1b4151-5ac6c58f\t\
+             SegmentId: 0a1b2c3d4e5f6a7b\tSampled: true\t\n",
+            REQUEST_ID
+        );
+        let report = match parse(&line) {
+            Some(PlatformLine::Report(report)) => report,
+            other => panic!("not a report: {:?}", other),
+        };
+        assert_eq!(report.duration_ms, 45.08);
+        assert_eq!(report.billed_duration_ms, 77);
+        assert_eq!(report.memory_size_mb, 1024);
+        assert_eq!(report.max_memory_used_mb, 31);
+        assert_eq!(report.init_duration_ms, Some(31.27));
+        assert_eq!(
+            report_text(&report),
+            format!("{} duration 45.08 ms billed 77 ms memory 31/1024 MB init 31.27 ms", REQUEST_ID)
+        );
+    }
+
+    #[test]
+    fn report_with_missing_fields_is_not_parsed() {
+        let line =
+            format!("REPORT RequestId: {}\tDuration: 1.52 ms\tMemory Size: 128 MB\t\n", REQUEST_ID);
+        assert_eq!(parse(&line), None);
+        let line = format!("REPORT RequestId: {}\tDuration: fast\t\n", REQUEST_ID);
+        assert_eq!(parse(&line), None);
+    }
+
+    #[test]
+    fn other_lines_are_not_platform_lines() {
+        assert_eq!(parse("Hello from the function\n"), None);
+        assert_eq!(parse("2024-01-01T00:00:00Z INFO REPORT RequestId: written by the app\n"), None);
+        assert_eq!(parse("START of the batch\n"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn messages_as_json() {
+        assert_eq!(
+            event_json(1700000000000, "hello\n", None),
+            r#"{"message":"hello","timestamp":1700000000000,"type":"message"}"#
+        );
+    }
+}
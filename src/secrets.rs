@@ -0,0 +1,69 @@
+use rusoto_secretsmanager::{GetSecretValueRequest, SecretsManager, SecretsManagerClient};
+use std::collections::HashMap;
+use std::process;
+use crate::AwsOpt;
+use crate::{aws, aws_log, exit};
+
+pub(crate) fn create_client(opt: &AwsOpt, region: &str) -> SecretsManagerClient {
+    aws::client(opt, region, SecretsManagerClient::new_with_client)
+}
+
+/// Reads the --lambda-env-secret KEY=SECRET references, exiting when one can't be read. Secrets
+/// given by name rather than ARN are looked up in `region`.
+pub(crate) fn resolve(
+    aws: &AwsOpt,
+    references: &[String],
+    region: Option<&str>,
+) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for reference in references {
+        let mut parts = reference.splitn(2, '=');
+        let (key, secret_id) = match (parts.next(), parts.next()) {
+            (Some(key), Some(secret_id)) => (key, secret_id),
+            _ => continue,
+        };
+        let arn_parts: Vec<_> = secret_id.split(':').collect();
+        let secret_region = match (arn_parts.len() >= 7, region) {
+            (true, _) => arn_parts[3],
+            (false, Some(region)) => region,
+            (false, None) => {
+                eprintln!(
+                    "Can't tell the region of secret {} for {}, give its full ARN",
+                    secret_id, key
+                );
+                process::exit(exit::BAD_ARGS);
+            }
+        };
+
+        let client = create_client(aws, secret_region);
+        let req = GetSecretValueRequest {
+            secret_id: secret_id.to_owned(),
+            ..Default::default()
+        };
+        // The response has the value in it, keep it out of --debug-aws
+        let max_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        let res = client.get_secret_value(req).sync();
+        log::set_max_level(max_level);
+
+        match res {
+            Ok(res) => match res.secret_string {
+                Some(value) => {
+                    println!("Read secret {} for {}", secret_id, key);
+                    values.insert(key.to_owned(), value);
+                }
+                None => {
+                    eprintln!("Secret {} for {} has no string value", secret_id, key);
+                    process::exit(exit::BAD_ARGS);
+                }
+            },
+            Err(e) => {
+                eprintln!("\n===== Reading secret {} for {} FAILED =====", secret_id, key);
+                eprintln!("{:#?}", e);
+                process::exit(exit::for_rusoto_error(&e));
+            }
+        }
+    }
+    aws_log::mask_values(values.values().cloned().collect());
+    values
+}
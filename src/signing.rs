@@ -0,0 +1,133 @@
+use rusoto_s3::{PutObjectRequest, S3, S3Client};
+use std::process;
+use std::time::{Duration, Instant, SystemTime};
+use crate::Opt;
+use crate::{aws, exit};
+
+/// How long a signing job may take, they usually finish in seconds
+const SIGNING_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Uploads the zip to the signing bucket, signs it with the signing profile and returns the bucket
+/// and key of the signed object
+pub(crate) fn sign(
+    opt: &Opt,
+    region: &str,
+    profile: &str,
+    bin: &str,
+    zip_data: bytes::Bytes,
+) -> (String, String) {
+    let bucket = match opt.signing_bucket {
+        Some(ref bucket) => bucket.clone(),
+        None => {
+            eprintln!("--signing-profile requires --signing-bucket");
+            process::exit(exit::BAD_ARGS);
+        }
+    };
+    // The signer API wants the profile name, also accept the profile ARN
+    let profile_name = profile.rsplit('/').next().unwrap_or(profile).to_owned();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let key = format!("unsigned/{}-{}.zip", bin, timestamp);
+
+    println!("Uploading unsigned zip to s3://{}/{}", bucket, key);
+    let s3_client = aws::client(&opt.aws, region, S3Client::new_with_client);
+    let req = PutObjectRequest {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        body: Some(zip_data.to_vec().into()),
+        ..Default::default()
+    };
+    let version = match s3_client.put_object(req).sync() {
+        Ok(res) => match res.version_id {
+            Some(version) => version,
+            None => {
+                eprintln!("Signing bucket {} must have versioning enabled", bucket);
+                process::exit(exit::BAD_ARGS);
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to upload zip for signing:\n{:#?}", e);
+            process::exit(exit::for_rusoto_error(&e));
+        }
+    };
+
+    println!("Signing with profile {}", profile_name);
+    // This version of rusoto has no signer crate, so its REST API is called directly
+    let body = serde_json::json!({
+        "clientRequestToken": format!("{}-{}", bin, timestamp),
+        "destination": { "s3": { "bucketName": bucket, "prefix": "signed/" } },
+        "profileName": profile_name,
+        "source": { "s3": { "bucketName": bucket, "key": key, "version": version } },
+    });
+    let res = signer_request(opt, region, "POST", "/signing-jobs", Some(body))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to start signing job:\n{}", e);
+            process::exit(exit::AWS_API);
+        });
+    let job_id = match res["jobId"].as_str() {
+        Some(job_id) => job_id.to_owned(),
+        None => {
+            eprintln!("Signing job without an id: {}", res);
+            process::exit(exit::AWS_API);
+        }
+    };
+
+    let deadline = Instant::now() + SIGNING_TIMEOUT;
+    loop {
+        let path = format!("/signing-jobs/{}", job_id);
+        let res = signer_request(opt, region, "GET", &path, None).unwrap_or_else(|e| {
+            eprintln!("Failed to describe signing job {}:\n{}", job_id, e);
+            process::exit(exit::AWS_API);
+        });
+
+        match res["status"].as_str() {
+            Some("Succeeded") => {
+                let signed = &res["signedObject"]["s3"];
+                match (signed["bucketName"].as_str(), signed["key"].as_str()) {
+                    (Some(bucket), Some(key)) => {
+                        println!("Signed object is s3://{}/{}", bucket, key);
+                        return (bucket.to_owned(), key.to_owned());
+                    }
+                    _ => {
+                        eprintln!("Signing job {} succeeded without a signed object", job_id);
+                        process::exit(exit::AWS_API);
+                    }
+                }
+            }
+            Some("Failed") => {
+                eprintln!(
+                    "Signing job {} failed: {}",
+                    job_id,
+                    res["statusReason"].as_str().unwrap_or_default()
+                );
+                process::exit(exit::AWS_API);
+            }
+            _ if Instant::now() >= deadline => {
+                eprintln!(
+                    "Signing job {} didn't finish in {} s",
+                    job_id,
+                    SIGNING_TIMEOUT.as_secs()
+                );
+                process::exit(exit::TIMEOUT);
+            }
+            _ => ::std::thread::sleep(Duration::from_secs(2)),
+        }
+    }
+}
+
+/// Sends a signer API request, returning the response on success
+fn signer_request(
+    opt: &Opt,
+    region: &str,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    let (status, res) = aws::send(&opt.aws, "signer", region, method, path, body)?;
+    if !(200..300).contains(&status) {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&res)));
+    }
+    serde_json::from_slice(&res).map_err(|e| format!("Unexpected response: {}", e))
+}
@@ -0,0 +1,111 @@
+use object::{Object, ObjectSection};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+use crate::Opt;
+
+/// Prints the size of the zip and of the bootstrap in it, compared with --size-baseline or the
+/// size recorded at the last deploy, warning when it grew more than --size-threshold percent
+pub(crate) fn report(zip_path: &Path, opt: &Opt) {
+    let zip_size = match fs::metadata(zip_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return,
+    };
+    let bootstrap = read_bootstrap(zip_path);
+    println!("\n===== Size of {} =====", zip_path.display());
+    println!("Zip:           {}", human(zip_size));
+    if let Some(ref bootstrap) = bootstrap {
+        println!("Bootstrap:     {} uncompressed", human(bootstrap.len() as u64));
+    }
+
+    let baseline = match opt.size_baseline {
+        Some(ref path) => read_baseline(path),
+        None => fs::read_to_string(recorded_path(zip_path))
+            .ok()
+            .and_then(|size| size.trim().parse().ok()),
+    };
+    if let Some(baseline) = baseline {
+        let delta = zip_size as f64 / baseline.max(1) as f64 * 100.0 - 100.0;
+        println!("Change:        {:+.1}% from {}", delta, human(baseline));
+        if delta > opt.size_threshold {
+            println!(
+                "Warning: the zip grew more than {}% (--size-threshold), which slows cold starts",
+                opt.size_threshold
+            );
+        }
+    }
+
+    if opt.size_details {
+        match bootstrap {
+            Some(ref bootstrap) => print_sections(bootstrap),
+            None => println!("No bootstrap in the zip to analyze"),
+        }
+    }
+}
+
+/// Records the zip's size for the next deploy to compare with
+pub(crate) fn record(zip_path: &Path) {
+    if let Ok(metadata) = fs::metadata(zip_path) {
+        fs::write(recorded_path(zip_path), metadata.len().to_string()).ok();
+    }
+}
+
+/// `<zip>.size` next to the zip, in target/lambda with the build state
+fn recorded_path(zip_path: &Path) -> PathBuf {
+    let mut path = zip_path.as_os_str().to_owned();
+    path.push(".size");
+    PathBuf::from(path)
+}
+
+/// The baseline from a zip to compare with or a file with the size in bytes
+fn read_baseline(path: &Path) -> Option<u64> {
+    let contents = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Warning: can't read --size-baseline {}: {}", path.display(), e);
+        Vec::new()
+    });
+    if contents.starts_with(b"PK") {
+        return Some(contents.len() as u64);
+    }
+    String::from_utf8_lossy(&contents).trim().parse().ok()
+}
+
+/// The bootstrap binary in the zip
+pub(crate) fn read_bootstrap(zip_path: &Path) -> Option<Vec<u8>> {
+    let mut archive = ZipArchive::new(File::open(zip_path).ok()?).ok()?;
+    let mut entry = archive.by_name("bootstrap").ok()?;
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+/// Lists the largest sections of the ELF binary, e.g. .text or .debug_info
+fn print_sections(binary: &[u8]) {
+    let file = match object::File::parse(binary) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Can't analyze the bootstrap: {}", e);
+            return;
+        }
+    };
+    let mut sections: Vec<(String, u64)> = file
+        .sections()
+        .map(|section| (section.name().unwrap_or("?").to_owned(), section.size()))
+        .filter(|(_, size)| *size > 0)
+        .collect();
+    sections.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    let total = binary.len().max(1) as f64;
+    println!("Largest sections:");
+    for (name, size) in sections.iter().take(10) {
+        println!("  {:<24} {:>10} {:>5.1}%", name, human(*size), *size as f64 / total * 100.0);
+    }
+}
+
+fn human(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::process::{self, Command};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+use crate::{docker, exit, size, Builder, Opt};
+
+/// Strips the symbol tables from the bootstrap in the zip for --strip and zips it again with fixed
+/// timestamps, so an unchanged binary gives the same zip and SHA-256
+pub(crate) fn strip_zip(zip_path: &Path, target: &str, opt: &Opt) {
+    let bootstrap = match size::read_bootstrap(zip_path) {
+        Some(bootstrap) => bootstrap,
+        None => {
+            eprintln!("No bootstrap in {} to strip", zip_path.display());
+            process::exit(1);
+        }
+    };
+    let zip_before = fs::metadata(zip_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+    let dir = zip_path.with_extension("strip");
+    fs::create_dir_all(&dir).expect("Can't create strip directory");
+    let bin_path = dir.join("bootstrap");
+    fs::write(&bin_path, &bootstrap).expect("Can't write bootstrap for stripping");
+    if !run_strip(&dir, target, opt) {
+        eprintln!(
+            "Can't strip {}: install llvm-strip{}",
+            zip_path.display(),
+            if target.starts_with("x86_64") { " or strip" } else { " (strip only handles x86_64)" }
+        );
+        process::exit(exit::TOOLCHAIN);
+    }
+    let stripped = fs::read(&bin_path).expect("Can't read stripped bootstrap");
+    fs::remove_dir_all(&dir).ok();
+
+    let zip_file = File::create(zip_path).expect("Can't create zip file");
+    let mut zip = ZipWriter::new(zip_file);
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o755)
+        .last_modified_time(DateTime::default());
+    zip.start_file("bootstrap", options).expect("Can't write zip file");
+    zip.write_all(&stripped).expect("Can't write zip file");
+    zip.finish().expect("Can't write zip file");
+
+    let zip_after = fs::metadata(zip_path).map(|metadata| metadata.len()).unwrap_or(0);
+    println!(
+        "Stripped {}: bootstrap {} -> {} bytes, zip {} -> {} bytes ({} saved)",
+        zip_path.display(),
+        bootstrap.len(),
+        stripped.len(),
+        zip_before,
+        zip_after,
+        zip_before.saturating_sub(zip_after)
+    );
+}
+
+/// Strips `dir`/bootstrap with llvm-strip, which handles every architecture, the host's strip for
+/// x86_64 on linux, or the build image's strip
+fn run_strip(dir: &Path, target: &str, opt: &Opt) -> bool {
+    let bin_path = dir.join("bootstrap");
+    if succeeds(Command::new("llvm-strip").arg("--strip-all").arg(&bin_path)) {
+        return true;
+    }
+    if !target.starts_with("x86_64") {
+        return false;
+    }
+    let host_x86 = cfg!(all(target_os = "linux", target_arch = "x86_64"));
+    if host_x86 && succeeds(Command::new("strip").arg("--strip-all").arg(&bin_path)) {
+        return true;
+    }
+    match opt.builder {
+        Builder::Docker => docker::strip(dir, opt),
+        _ => false,
+    }
+}
+
+/// Runs the command quietly, whether it ran and succeeded
+fn succeeds(cmd: &mut Command) -> bool {
+    cmd.output().map(|output| output.status.success()).unwrap_or(false)
+}
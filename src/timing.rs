@@ -0,0 +1,87 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Durations of the deploy's phases in the order they ran, phases run several times (e.g. an
+/// upload per function) are added up
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// How often a long phase tells it's still running
+const TICK: Duration = Duration::from_secs(30);
+
+/// Runs `f` as `phase`, recording how long it took
+pub(crate) fn record<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+
+    let mut phases = PHASES.lock().unwrap();
+    match phases.iter_mut().find(|(name, _)| *name == phase) {
+        Some((_, total)) => *total += elapsed,
+        None => phases.push((phase, elapsed)),
+    }
+    result
+}
+
+/// Like `record`, printing the elapsed time on stderr every 30 s so a silent phase doesn't look hung
+pub(crate) fn record_ticking<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let _ticker = Ticker::start(phase);
+    record(phase, f)
+}
+
+/// The recorded phases with their durations in seconds
+pub(crate) fn phases() -> Vec<(&'static str, f64)> {
+    PHASES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, duration)| (*name, duration.as_secs_f64()))
+        .collect()
+}
+
+/// Prints the recorded phases and their total
+pub(crate) fn print_table() {
+    let phases = phases();
+    if phases.is_empty() {
+        return;
+    }
+    println!("\n===== Timing =====");
+    for (name, secs) in &phases {
+        println!("{:<20} {:>8.1} s", name, secs);
+    }
+    println!("{:<20} {:>8.1} s", "total", phases.iter().map(|(_, secs)| secs).sum::<f64>());
+}
+
+/// Prints the elapsed time of a phase until dropped
+struct Ticker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Ticker {
+    fn start(phase: &'static str) -> Ticker {
+        let (stop, stopped) = mpsc::channel();
+        let started = Instant::now();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+                let secs = started.elapsed().as_secs();
+                eprintln!("[{} running for {}m {:02}s]", phase, secs / 60, secs % 60);
+            }
+        });
+        Ticker {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
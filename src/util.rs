@@ -1,38 +1,609 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::process::Command;
 use std::process;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use toml::Value;
+use crate::exit;
+use crate::Opt;
 
-pub fn parse_arn_or_key(raw: &str) -> (String, String) {
-    if raw.split(":").count() != 7 {
-        if let Ok(mut lambda_toml_file) = File::open("Lambda.toml") {
-            let cargo_toml: Value = {
-                let mut data = String::new();
-                lambda_toml_file
-                    .read_to_string(&mut data)
-                    .expect("Can't read ./Lambda.toml");
-                toml::from_str(&data).expect("Can't parse ./Lambda.toml")
+static WORKSPACE_ROOT: OnceLock<PathBuf> = OnceLock::new();
+/// Lambda.toml given with --config
+static LAMBDA_TOML: OnceLock<PathBuf> = OnceLock::new();
+
+/// Lambda.toml, read once
+static PARSED_LAMBDA_TOML: OnceLock<LambdaToml> = OnceLock::new();
+
+/// The settings of Lambda.toml
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LambdaToml {
+    pub(crate) default: Option<String>,
+    #[serde(default)]
+    pub(crate) arns: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) docker_args: Vec<String>,
+    #[serde(default)]
+    pub(crate) docker_cmd: Vec<String>,
+    #[serde(default)]
+    pub(crate) images: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) env_files: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) build: HashMap<String, BuildConfig>,
+    #[serde(default)]
+    pub(crate) checks: Vec<String>,
+    #[serde(default)]
+    pub(crate) pre_build: Vec<String>,
+    #[serde(default)]
+    pub(crate) post_deploy: Vec<String>,
+    #[serde(default)]
+    pub(crate) hooks: HashMap<String, HooksConfig>,
+    #[serde(default)]
+    pub(crate) include: Vec<IncludeConfig>,
+}
+
+/// An entry of `include`, a glob or a glob with the directory in the zip to put its matches in
+pub(crate) enum IncludeConfig {
+    Pattern(String),
+    Mapped(MappedInclude),
+}
+
+/// A `{ from = "<glob>", to = "<dir in zip>" }` entry of `include`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MappedInclude {
+    pub(crate) from: String,
+    pub(crate) to: Option<String>,
+}
+
+// Not untagged, whose error wouldn't tell what's wrong with a table
+impl<'de> Deserialize<'de> for IncludeConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IncludeVisitor;
+
+        impl<'de> Visitor<'de> for IncludeVisitor {
+            type Value = IncludeConfig;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a glob or a table with `from` and an optional `to`")
+            }
+
+            fn visit_str<E: de::Error>(self, pattern: &str) -> Result<IncludeConfig, E> {
+                Ok(IncludeConfig::Pattern(pattern.to_owned()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<IncludeConfig, A::Error> {
+                let mapped = MappedInclude::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(IncludeConfig::Mapped(mapped))
+            }
+        }
+
+        deserializer.deserialize_any(IncludeVisitor)
+    }
+}
+
+/// A function's table under [hooks]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HooksConfig {
+    #[serde(default)]
+    pub(crate) pre_build: Vec<String>,
+    #[serde(default)]
+    pub(crate) post_deploy: Vec<String>,
+}
+
+/// A function's table under [build]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BuildConfig {
+    #[serde(default)]
+    pub(crate) features: Vec<String>,
+    #[serde(default)]
+    pub(crate) all_features: bool,
+    #[serde(default)]
+    pub(crate) no_default_features: bool,
+    pub(crate) memory: Option<String>,
+    pub(crate) cpus: Option<f64>,
+}
+
+/// Uses `path` instead of ./Lambda.toml, failing when it's missing
+pub(crate) fn set_lambda_toml(path: &Path) {
+    if !path.is_file() {
+        eprintln!("Config file {} doesn't exist", path.display());
+        process::exit(exit::BAD_ARGS);
+    }
+    LAMBDA_TOML.set(path.to_path_buf()).ok();
+}
+
+/// The --config file or ./Lambda.toml if there's one, falling back to the one in the workspace root
+pub(crate) fn lambda_toml_path() -> Option<PathBuf> {
+    if let Some(path) = LAMBDA_TOML.get() {
+        return Some(path.clone());
+    }
+    let local = PathBuf::from("Lambda.toml");
+    if local.is_file() {
+        return Some(local);
+    }
+    let cwd = env::current_dir().ok()?;
+    let root = WORKSPACE_ROOT.get_or_init(|| workspace_root(&cwd));
+    Some(root.join("Lambda.toml")).filter(|path| path.is_file())
+}
+
+/// The Lambda.toml file read, for messages about its contents
+pub(crate) fn lambda_toml_name() -> String {
+    lambda_toml_path().map_or_else(|| "Lambda.toml".to_owned(), |path| path.display().to_string())
+}
+
+/// The settings of the file of `lambda_toml_path`, empty when there's none. It's read and parsed
+/// on first use, exiting when it's invalid.
+pub(crate) fn lambda_toml() -> &'static LambdaToml {
+    PARSED_LAMBDA_TOML.get_or_init(|| {
+        let path = match lambda_toml_path() {
+            Some(path) => path,
+            None => return LambdaToml::default(),
+        };
+        let data = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("Can't read {}: {}", path.display(), e);
+            process::exit(exit::BAD_ARGS);
+        });
+        parse_lambda_toml(&data).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(exit::BAD_ARGS);
+        })
+    })
+}
+
+/// Parses Lambda.toml. toml's errors name the offending key and its line, e.g. `invalid type:
+/// integer `1`, expected a string for key `arns.prod` at line 3 column 8`.
+fn parse_lambda_toml(data: &str) -> Result<LambdaToml, String> {
+    toml::from_str(data).map_err(|e| e.to_string())
+}
+
+/// Resolves the crate directory from a path to its Cargo.toml
+pub fn project_dir_of(manifest_path: &Path) -> PathBuf {
+    let manifest_path = manifest_path.canonicalize().unwrap_or_else(|e| {
+        eprintln!("Can't find manifest {}: {}", manifest_path.display(), e);
+        process::exit(exit::BAD_ARGS);
+    });
+    match manifest_path.parent() {
+        Some(dir) if manifest_path.is_file() => dir.to_path_buf(),
+        _ => {
+            eprintln!("{} is not a Cargo.toml file", manifest_path.display());
+            process::exit(exit::BAD_ARGS);
+        }
+    }
+}
+
+/// The workspace root of the crate in `dir`, the crate directory itself outside workspaces or when
+/// cargo can't tell (e.g. it's not installed on the host)
+pub fn workspace_root(dir: &Path) -> PathBuf {
+    Command::new("cargo")
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+            manifest.parent().map(Path::to_path_buf)
+        })
+        .unwrap_or_else(|| dir.to_path_buf())
+}
+
+/// Names of the bin targets of the workspace packages, or only of `package`, from cargo metadata
+/// or, when cargo isn't available on the host, from the Cargo.toml files. None when neither works.
+pub fn bin_targets(project_dir: &Path, package: Option<&str>) -> Option<Vec<String>> {
+    metadata_bin_targets(project_dir, package).or_else(|| manifest_bin_targets(project_dir, package))
+}
+
+fn metadata_bin_targets(project_dir: &Path, package: Option<&str>) -> Option<Vec<String>> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .current_dir(project_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+
+    let empty = Vec::new();
+    let bins = metadata["packages"]
+        .as_array()
+        .unwrap_or(&empty)
+        .iter()
+        .filter(|p| package.is_none_or(|name| p["name"] == name))
+        .flat_map(|p| p["targets"].as_array().unwrap_or(&empty))
+        .filter(|t| t["kind"].as_array().is_some_and(|kinds| kinds.iter().any(|k| k == "bin")))
+        .filter_map(|t| t["name"].as_str().map(str::to_owned))
+        .collect();
+    Some(bins)
+}
+
+/// Bin targets from the `[[bin]]` tables and the auto-discovered src/main.rs and src/bin of the
+/// Cargo.toml in `project_dir` and its workspace members
+fn manifest_bin_targets(project_dir: &Path, package: Option<&str>) -> Option<Vec<String>> {
+    let data = std::fs::read_to_string(project_dir.join("Cargo.toml")).ok()?;
+    let cargo_toml: Value = toml::from_str(&data).ok()?;
+
+    let mut bins = Vec::new();
+    let name = cargo_toml.get("package").and_then(|p| p.get("name")).and_then(Value::as_str);
+    if let Some(name) = name {
+        if package.is_none_or(|package| package == name) {
+            bins.extend(package_bin_targets(project_dir, name, &cargo_toml));
+        }
+    }
+
+    let members = cargo_toml
+        .get("workspace")
+        .and_then(|w| w.get("members"))
+        .and_then(Value::as_array);
+    for member in members.into_iter().flatten().filter_map(Value::as_str) {
+        // Only trailing `/*` globs, the common `crates/*` layout
+        let dirs: Vec<PathBuf> = match member.strip_suffix("/*") {
+            Some(parent) => std::fs::read_dir(project_dir.join(parent))
+                .map(|entries| entries.filter_map(Result::ok).map(|e| e.path()).collect())
+                .unwrap_or_default(),
+            None => vec![project_dir.join(member)],
+        };
+        for dir in dirs {
+            if let Some(member_bins) = manifest_bin_targets(&dir, package) {
+                bins.extend(member_bins);
+            }
+        }
+    }
+    Some(bins)
+}
+
+fn package_bin_targets(dir: &Path, name: &str, cargo_toml: &Value) -> Vec<String> {
+    let mut bins: Vec<String> = cargo_toml
+        .get("bin")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|bin| bin.get("name").and_then(Value::as_str).map(str::to_owned))
+        .collect();
+
+    let autobins = cargo_toml
+        .get("package")
+        .and_then(|p| p.get("autobins"))
+        .and_then(Value::as_bool)
+        .unwrap_or(true);
+    if !autobins {
+        return bins;
+    }
+    if dir.join("src/main.rs").is_file() {
+        bins.push(name.to_owned());
+    }
+    if let Ok(entries) = std::fs::read_dir(dir.join("src/bin")) {
+        for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+            let is_bin =
+                path.extension().is_some_and(|ext| ext == "rs") || path.join("main.rs").is_file();
+            let stem = path.file_stem().and_then(|stem| stem.to_str());
+            if let (true, Some(stem)) = (is_bin, stem) {
+                bins.push(stem.to_owned());
+            }
+        }
+    }
+    bins.sort();
+    bins.dedup();
+    bins
+}
+
+/// Edit distance between two strings, for suggesting the intended name on typos
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The package name from the Cargo.toml in `dir`
+pub fn package_name(dir: &Path) -> Option<String> {
+    let data = std::fs::read_to_string(dir.join("Cargo.toml")).ok()?;
+    let cargo_toml: Value = toml::from_str(&data).ok()?;
+    cargo_toml.get("package")?.get("name")?.as_str().map(str::to_owned)
+}
+
+/// The cargo target directory of the project, honoring `CARGO_TARGET_DIR` and `build.target-dir`
+/// in .cargo/config.toml like cargo does
+pub fn target_dir(project_dir: &Path) -> PathBuf {
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(ref dir) if !dir.is_empty() => project_dir.join(dir),
+        _ => config_target_dir(project_dir).unwrap_or_else(|| project_dir.join("target")),
+    }
+}
+
+/// CARGO_HOME or ~/.cargo like cargo defaults to, created when missing. None without a home
+/// directory.
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    if let Some(cargo_home) = env::var_os("CARGO_HOME") {
+        return Some(PathBuf::from(cargo_home));
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    let cargo_home = Path::new(&home).join(".cargo");
+    std::fs::create_dir_all(cargo_home.join("registry")).ok()?;
+    Some(cargo_home)
+}
+
+/// `build.target-dir` from the cargo config files of the project, its ancestors and CARGO_HOME,
+/// relative paths are resolved against the directory containing .cargo
+fn config_target_dir(project_dir: &Path) -> Option<PathBuf> {
+    let config_dirs = project_dir
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home());
+
+    for config_dir in config_dirs {
+        for name in &["config.toml", "config"] {
+            let data = match std::fs::read_to_string(config_dir.join(name)) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            let config: Value = match toml::from_str(&data) {
+                Ok(config) => config,
+                Err(_) => continue,
             };
+            let target_dir = config
+                .get("build")
+                .and_then(|build| build.get("target-dir"))
+                .and_then(|dir| dir.as_str());
+            if let Some(target_dir) = target_dir {
+                let base = config_dir.parent().unwrap_or(&config_dir);
+                return Some(base.join(target_dir));
+            }
+        }
+    }
+    None
+}
+
+/// Extra cargo arguments from --package, --features, --all-features, --no-default-features,
+/// --locked, --offline and --cargo-flag
+pub(crate) fn cargo_args(opt: &Opt) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(ref package) = opt.package {
+        args.push("--package".to_owned());
+        args.push(package.clone());
+    }
+    if !opt.features.is_empty() {
+        args.push("--features".to_owned());
+        args.push(opt.features.join(","));
+    }
+    if opt.all_features {
+        args.push("--all-features".to_owned());
+    }
+    if opt.no_default_features {
+        args.push("--no-default-features".to_owned());
+    }
+    if opt.locked {
+        args.push("--locked".to_owned());
+    }
+    if opt.offline {
+        args.push("--offline".to_owned());
+    }
+    if let Some(ref format) = opt.message_format {
+        args.push("--message-format".to_owned());
+        args.push(format.clone());
+    }
+    args.extend(opt.cargo_flags.iter().cloned());
+    args
+}
 
-            let arn = cargo_toml
-                .get("arns")
-                .and_then(|arns| arns.get(raw))
-                .and_then(|v| v.as_str());
+/// Directory under target/<triple> cargo builds `profile` into, e.g. debug for dev
+pub(crate) fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// Channel of the rust-toolchain.toml (or legacy rust-toolchain) file in `dir`
+pub(crate) fn toolchain_channel(dir: &Path) -> Option<String> {
+    let data = ["rust-toolchain.toml", "rust-toolchain"]
+        .iter()
+        .find_map(|name| std::fs::read_to_string(dir.join(name)).ok())?;
+    match data.parse::<Value>() {
+        Ok(toml) => toml
+            .get("toolchain")
+            .and_then(|toolchain| toolchain.get("channel"))
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        // The legacy file can hold just the channel name
+        Err(_) => data.lines().next().map(str::trim).filter(|line| !line.is_empty()).map(str::to_owned),
+    }
+}
+
+/// Fails unless the dependencies are vendored (vendor/ with a source replacement in the cargo
+/// config) or `cargo fetch --offline` finds them all in the registry cache
+pub(crate) fn check_offline(project_dir: &Path) {
+    let replaced = ["config.toml", "config"].iter().any(|name| {
+        std::fs::read_to_string(project_dir.join(".cargo").join(name))
+            .map(|config| config.contains("replace-with"))
+            .unwrap_or(false)
+    });
+    if project_dir.join("vendor").is_dir() && replaced {
+        return;
+    }
 
-            if let Some(value) = arn {
-                return parse_arn(value);
+    let output = match Command::new("cargo")
+        .args(["fetch", "--offline"])
+        .current_dir(project_dir)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Warning: can't check the offline dependencies without cargo: {}", e);
+            return;
+        }
+    };
+    if output.status.success() {
+        return;
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // e.g. "failed to download `serde v1.0.100`" or "no matching package named `serde` found"
+    let mut missing: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.contains("failed to download") || line.contains("no matching package"))
+        .filter_map(|line| line.split('`').nth(1))
+        .collect();
+    missing.dedup();
+    eprintln!("--offline needs the dependencies vendored or in the cargo registry cache");
+    if missing.is_empty() {
+        eprintln!("{}", stderr.trim());
+    } else {
+        eprintln!("Missing crates:");
+        for krate in missing {
+            eprintln!("  {}", krate);
+        }
+    }
+    eprintln!("Run `cargo fetch` or `cargo vendor` while online first");
+    process::exit(1);
+}
+
+/// Whether our output and cargo's are colored, following --color
+pub(crate) fn color_enabled(opt: &Opt) -> bool {
+    match opt.color.as_str() {
+        "always" => true,
+        "never" => false,
+        _ => io::stdout().is_terminal(),
+    }
+}
+
+/// Parses KEY=VALUE lines of an env file, skipping blank lines and # comments
+pub fn read_env_file(path: &Path) -> Vec<(String, String)> {
+    let mut data = String::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut data)) {
+        eprintln!("Can't read env file {}: {}", path.display(), e);
+        process::exit(exit::BAD_ARGS);
+    }
+
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim().to_owned();
+            let value = parts.next().unwrap_or("").to_owned();
+            (key, value)
+        })
+        .collect()
+}
+
+/// Parses a .env file for the function's environment: KEY=VALUE lines with optional `export`,
+/// blank lines and # comments skipped and quotes around values removed
+pub fn read_dotenv_file(path: &Path) -> Vec<(String, String)> {
+    let data = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Can't read env file {}: {}", path.display(), e);
+        process::exit(exit::BAD_ARGS);
+    });
+
+    let mut vars = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => {
+                eprintln!("{}:{}: expected KEY=VALUE", path.display(), i + 1);
+                process::exit(exit::BAD_ARGS);
+            }
+        };
+        vars.push((key.to_owned(), unquote(value)));
+    }
+    vars
+}
+
+/// Removes matching quotes around a value, unescaping \n, \" and \\ inside double quotes
+fn unquote(value: &str) -> String {
+    let quoted = |quote: char| value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote);
+    if quoted('\'') {
+        value[1..value.len() - 1].to_owned()
+    } else if quoted('"') {
+        let mut unescaped = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => unescaped.push('\n'),
+                ('\\', Some(escaped @ ('"' | '\\'))) => unescaped.push(escaped),
+                _ => {
+                    unescaped.push(c);
+                    continue;
+                }
             }
+            chars.next();
+        }
+        unescaped
+    } else {
+        value.to_owned()
+    }
+}
+
+pub fn parse_arn_or_key(raw: &str) -> (String, String) {
+    if raw.split(":").count() != 7 {
+        if let Some(arn) = lambda_toml().arns.get(raw) {
+            return parse_arn(arn);
         }
     }
     parse_arn(raw)
 }
 
+/// Region and name of the function in each of `regions`, or of the ARN (or key to Lambda.toml)
+/// when there are none. With regions, `raw` can also be a plain function name.
+pub fn function_regions(raw: &str, regions: &[String]) -> Vec<(String, String)> {
+    if regions.is_empty() {
+        return vec![parse_arn_or_key(raw)];
+    }
+    let func_name = match lambda_toml().arns.get(raw) {
+        Some(arn) => parse_arn(arn).1,
+        None if raw.contains(':') => parse_arn(raw).1,
+        None => raw.to_owned(),
+    };
+    regions.iter().map(|region| (region.clone(), func_name.clone())).collect()
+}
+
+/// Region and name of the function like `function_regions` (the first region when there are
+/// several), None instead of exiting when the ARN can't be resolved
+pub fn try_function_region(raw: &str, regions: &[String]) -> Option<(String, String)> {
+    let arn = lambda_toml().arns.get(raw).cloned().unwrap_or_else(|| raw.to_owned());
+    let parts: Vec<_> = arn.split(':').collect();
+    match (parts.len(), regions.first()) {
+        (7, Some(region)) => Some((region.clone(), parts[6].to_owned())),
+        (7, None) => Some((parts[3].to_owned(), parts[6].to_owned())),
+        (1, Some(region)) => Some((region.clone(), arn)),
+        _ => None,
+    }
+}
+
 fn parse_arn(raw: &str) -> (String, String) {
     let arn: Vec<_> = raw.split(":").collect();
     if arn.len() != 7 {
-        eprintln!("Unidentified ARN, should be like arn:aws:lambda:<region>:<account id>:function:<function name> or a key to Lambda.toml");
-        process::exit(1);
+        eprintln!(
+            "Unidentified ARN, should be like arn:aws:lambda:<region>:<account id>:function:<function name> \
+             or a key to table [arns] in {}",
+            lambda_toml_name()
+        );
+        process::exit(exit::BAD_ARGS);
     }
 
     let region = arn[3];
@@ -40,6 +611,292 @@ fn parse_arn(raw: &str) -> (String, String) {
     (region.to_string(), func_name.to_string())
 }
 
+pub fn parse_non_empty(raw: &str) -> Result<String, String> {
+    if raw.trim().is_empty() {
+        Err("Value can't be empty".to_owned())
+    } else {
+        Ok(raw.to_owned())
+    }
+}
+
+/// Accepts payloads that parse as JSON
+pub fn parse_json(raw: &str) -> Result<String, String> {
+    serde_json::from_str::<serde_json::Value>(raw)
+        .map(|_| raw.to_owned())
+        .map_err(|e| format!("Invalid JSON: {}", e))
+}
+
+pub fn parse_key_value(raw: &str) -> Result<String, String> {
+    match raw.find('=') {
+        Some(i) if i > 0 => Ok(raw.to_owned()),
+        _ => Err("Expected KEY=VALUE".to_owned()),
+    }
+}
+
+/// Accepts SQS queue and SNS topic ARNs, the only dead-letter targets Lambda supports
+pub fn parse_dlq_arn(raw: &str) -> Result<String, String> {
+    let service = raw.split(':').nth(2).unwrap_or("");
+    if raw.starts_with("arn:") && (service == "sqs" || service == "sns") {
+        Ok(raw.to_owned())
+    } else {
+        Err("Expected an SQS queue or SNS topic ARN".to_owned())
+    }
+}
+
+/// Parses durations like `90`, `30s`, `5m` or `2h`
+pub fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let (number, multiplier) = match raw.chars().last() {
+        Some('s') => (&raw[..raw.len() - 1], 1),
+        Some('m') => (&raw[..raw.len() - 1], 60),
+        Some('h') => (&raw[..raw.len() - 1], 60 * 60),
+        _ => (raw, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Invalid duration {}, use e.g. 30s, 5m or 1h", raw))
+}
+
+/// Splits an `s3://bucket/key` URL into bucket and key
+pub fn parse_s3_url(raw: &str) -> (String, String) {
+    let mut parts = raw.trim_start_matches("s3://").splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(bucket), Some(key)) if raw.starts_with("s3://") && !bucket.is_empty() && !key.is_empty() => {
+            (bucket.to_owned(), key.to_owned())
+        }
+        _ => {
+            eprintln!("Invalid S3 URL {}, should be like s3://<bucket>/<key>", raw);
+            process::exit(exit::BAD_ARGS);
+        }
+    }
+}
+
+/// AWS console links to the function page and its log group, the partition is taken from the ARN
+pub fn console_urls(function_arn: &str, region: &str, func_name: &str) -> (String, String) {
+    let base = match function_arn.split(':').nth(1) {
+        Some("aws-us-gov") => "https://console.amazonaws-us-gov.com".to_owned(),
+        Some("aws-cn") => "https://console.amazonaws.cn".to_owned(),
+        _ => format!("https://{}.console.aws.amazon.com", region),
+    };
+    let function_url = format!(
+        "{}/lambda/home?region={}#/functions/{}",
+        base, region, func_name
+    );
+    let logs_url = format!(
+        "{}/cloudwatch/home?region={}#logsV2:log-groups/log-group/$252Faws$252Flambda$252F{}",
+        base, region, func_name
+    );
+    (function_url, logs_url)
+}
+
+/// Lines describing the added (+), changed (~) and removed (-) variables between two
+/// environments, sorted by key and with secret-looking values and those of `secret_keys` masked
+pub fn env_diff(
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+    secret_keys: &[&str],
+) -> Vec<String> {
+    let shown = |key: &str, value: &str| {
+        if secret_keys.contains(&key) {
+            "****".to_owned()
+        } else {
+            mask(key, value)
+        }
+    };
+
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| match (old.get(key), new.get(key)) {
+            (None, Some(value)) => Some(format!("+ {}={}", key, shown(key, value))),
+            (Some(before), Some(after)) if before != after => Some(format!(
+                "~ {}: {} -> {}",
+                key,
+                shown(key, before),
+                shown(key, after)
+            )),
+            (Some(_), None) => Some(format!("- {}", key)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Hides values of keys that look like secrets and of URLs with credentials in them
+fn mask(key: &str, value: &str) -> String {
+    const SECRET_WORDS: &[&str] =
+        &["SECRET", "PASSWORD", "PASSWD", "TOKEN", "KEY", "CREDENTIAL", "PRIVATE"];
+    let key = key.to_uppercase();
+    let url_credentials = value.contains("://") && value.contains('@');
+    if SECRET_WORDS.iter().any(|word| key.contains(word)) || url_credentials {
+        "****".to_owned()
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Git commit, dirty state and branch of the project (left out outside git repositories) and the
+/// build time, for the build to embed
+pub fn build_metadata(project_dir: &Path) -> Vec<(&'static str, String)> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(project_dir)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+
+    let mut metadata = Vec::new();
+    if let Some(sha) = git(&["rev-parse", "HEAD"]) {
+        metadata.push(("GIT_SHA", sha));
+        let dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+        metadata.push(("GIT_DIRTY", dirty.to_string()));
+        if let Some(branch) = git(&["rev-parse", "--abbrev-ref", "HEAD"]) {
+            metadata.push(("GIT_BRANCH", branch));
+        }
+    }
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    metadata.push(("BUILD_TIMESTAMP", timestamp.to_string()));
+    metadata
+}
+
+/// Runs `command` with the shell, `sh -c` or `cmd /C` on windows
+pub fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+pub fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
+    answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes")
+}
+
+/// Hash of the project's source files, the included assets, the cargo flags, the build
+/// environment, the target and the build image that changes whenever a build could produce a
+/// different zip
+pub(crate) fn build_state(project_dir: &Path, target: &str, opt: &Opt) -> String {
+    // Included files may be ignored by git, e.g. a downloaded database
+    let included = crate::assets::files(project_dir);
+    let image_id = match opt.builder {
+        crate::Builder::Docker => crate::docker::image_id(opt).unwrap_or_default(),
+        _ => String::new(),
+    };
+    hash_build_inputs(project_dir, target, opt, &included, &image_id)
+}
+
+fn hash_build_inputs(
+    project_dir: &Path,
+    target: &str,
+    opt: &Opt,
+    included: &[(PathBuf, String)],
+    image_id: &str,
+) -> String {
+    let mut hasher = Sha256::new();
+    let mut files = source_files(project_dir);
+    files.sort();
+    for file in files {
+        hasher.input(file.to_string_lossy().as_bytes());
+        hasher.input(std::fs::read(project_dir.join(&file)).unwrap_or_default());
+    }
+    for (file, name) in included {
+        hasher.input(name.as_bytes());
+        hasher.input(std::fs::read(file).unwrap_or_default());
+    }
+    hasher.input(cargo_args(opt).join(" ").as_bytes());
+    hasher.input(target.as_bytes());
+    hasher.input(
+        format!(
+            "{:?} {} {} {} {} {}",
+            opt.builder,
+            opt.keep_debug_info,
+            opt.strip,
+            opt.profile_name,
+            opt.glibc_version,
+            opt.no_normalize_zip
+        )
+        .as_bytes(),
+    );
+    // BUILD_TIMESTAMP differs on every run and would never let the build be skipped
+    for env in opt.env.iter().filter(|env| !env.starts_with("BUILD_TIMESTAMP=")) {
+        hasher.input(env.as_bytes());
+        hasher.input(b" ");
+    }
+    // The variables of the env file reach cargo
+    if let Some(ref env_file) = opt.env_file {
+        hasher.input(std::fs::read(env_file).unwrap_or_default());
+    }
+    hasher.input(format!("{:?} {:?}", opt.docker_args, opt.docker_cmd).as_bytes());
+    hasher.input(image_id.as_bytes());
+    format!("{:x}", hasher.result())
+}
+
+/// Files of the project relative to it: the ones git tracks or doesn't ignore, or without git
+/// everything outside target and hidden directories
+fn source_files(project_dir: &Path) -> Vec<PathBuf> {
+    let output = Command::new("git")
+        .args(["ls-files", "--cached", "--others", "--exclude-standard"])
+        .current_dir(project_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    if let Some(output) = output {
+        return String::from_utf8_lossy(&output.stdout).lines().map(PathBuf::from).collect();
+    }
+
+    fn walk(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name();
+            let relative = relative.join(&name);
+            let hidden = name.to_string_lossy().starts_with('.');
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() && !hidden && relative != Path::new("target") => {
+                    walk(&entry.path(), &relative, files)
+                }
+                Ok(kind) if kind.is_file() => files.push(relative),
+                _ => {}
+            }
+        }
+    }
+    let mut files = Vec::new();
+    walk(project_dir, Path::new(""), &mut files);
+    files
+}
+
+/// Base64 SHA-256 of the zip, as Lambda reports it in CodeSha256
+pub fn code_sha_256(zip_data: &[u8]) -> String {
+    base64::encode(&Sha256::digest(zip_data))
+}
+
+/// 64-bit FNV-1a, stable across Rust versions unlike `DefaultHasher`
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 pub trait CommandExt {
     fn status_bool(&mut self) -> bool;
 }
@@ -50,3 +907,156 @@ impl CommandExt for Command {
         result.map(|r| r.success()).unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn env_diff_lines() {
+        let env = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let old = env(&[
+            ("LOG_LEVEL", "info"),
+            ("REGION", "eu-west-1"),
+            ("API_TOKEN", "abc"),
+            ("DATABASE_URL", "postgres://app:hunter2@db/app"),
+            ("LEGACY", "1"),
+            ("SECRET_ARN", "arn:aws:secretsmanager:eu-west-1:1:secret:db"),
+        ]);
+        let new = env(&[
+            ("LOG_LEVEL", "debug"),
+            ("REGION", "eu-west-1"),
+            ("API_TOKEN", "def"),
+            ("DATABASE_URL", "postgres://app:hunter3@db/app"),
+            ("FEATURE", "on"),
+            ("SECRET_ARN", "arn:aws:secretsmanager:eu-west-1:1:secret:db"),
+            ("DB_PASS", "from-secrets-manager"),
+        ]);
+        assert_eq!(
+            env_diff(&old, &new, &["DB_PASS"]),
+            [
+                "~ API_TOKEN: **** -> ****",
+                "~ DATABASE_URL: **** -> ****",
+                "+ DB_PASS=****",
+                "+ FEATURE=on",
+                "- LEGACY",
+                "~ LOG_LEVEL: info -> debug",
+            ]
+        );
+        assert!(env_diff(&new, &new, &[]).is_empty());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        for raw in &["", "h", "-5m", "1.5h", "5d", "9999999999999999h", "18446744073709551616"] {
+            assert!(parse_duration(raw).is_err(), "{:?} was accepted", raw);
+        }
+    }
+
+    #[test]
+    fn lambda_toml_settings() {
+        let lambda_toml = parse_lambda_toml(
+            "default = \"prod\"\n\
+             pre_build = [\"make assets\"]\n\
+             [arns]\n\
+             prod = \"arn:aws:lambda:eu-west-1:123456789012:function:api\"\n\
+             [build.prod]\n\
+             features = [\"tls\"]\n\
+             cpus = 2\n",
+        )
+        .unwrap();
+        assert_eq!(lambda_toml.default.as_deref(), Some("prod"));
+        assert_eq!(lambda_toml.pre_build, ["make assets"]);
+        assert_eq!(lambda_toml.arns["prod"], "arn:aws:lambda:eu-west-1:123456789012:function:api");
+        assert_eq!(lambda_toml.build["prod"].features, ["tls"]);
+        assert_eq!(lambda_toml.build["prod"].cpus, Some(2.0));
+    }
+
+    #[test]
+    fn lambda_toml_errors_name_the_key() {
+        let error = |data: &str| parse_lambda_toml(data).err().unwrap_or_default();
+        let not_string = error("[arns]\nprod = 1\n");
+        assert!(not_string.contains("expected a string for key `arns.prod`"), "{}", not_string);
+        let unknown = error("docker_arg = [\"--network=host\"]\n");
+        assert!(unknown.contains("unknown field `docker_arg`"), "{}", unknown);
+    }
+
+    #[test]
+    fn include_entries() {
+        let parse = |data: &str| parse_lambda_toml(data).map(|_| ());
+        assert_eq!(parse(r#"include = ["templates/**/*", { from = "data/*.mmdb", to = "geoip" }]"#), Ok(()));
+        assert_eq!(parse(r#"include = [{ from = "data/*.mmdb" }]"#), Ok(()));
+
+        let typo = parse(r#"include = [{ form = "data/*.mmdb" }]"#).unwrap_err();
+        assert!(typo.contains("unknown field `form`") && typo.contains("key `include`"), "{}", typo);
+        let missing = parse(r#"include = [{ to = "geoip" }]"#).unwrap_err();
+        assert!(missing.contains("missing field `from`"), "{}", missing);
+        let invalid = parse("include = [1]").unwrap_err();
+        assert!(invalid.contains("a glob or a table"), "{}", invalid);
+    }
+
+    #[test]
+    fn build_state_is_stable_across_runs() {
+        let dir = env::temp_dir().join(format!("cargo-aws-lambda-state-{}", process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+        let target = "x86_64-unknown-linux-musl";
+
+        let state = |timestamp: &str| {
+            let mut opt = Opt::from_iter(&["cargo-aws-lambda", "func", "--builder", "native"]);
+            opt.env.push("GIT_SHA=abc123".to_owned());
+            opt.env.push(format!("BUILD_TIMESTAMP={}", timestamp));
+            build_state(&dir, target, &opt)
+        };
+        let (first, second) = (state("1700000000"), state("1700000042"));
+        std::fs::write(dir.join("src").join("main.rs"), "fn main() { println!(); }\n").unwrap();
+        let changed = state("1700000042");
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(first, second);
+        assert_ne!(first, changed);
+    }
+
+    #[test]
+    fn build_state_changes_with_build_inputs() {
+        let dir = env::temp_dir().join(format!("cargo-aws-lambda-inputs-{}", process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+        let (env_file, asset) = (dir.join("build.env"), dir.join("GeoLite2.mmdb"));
+        std::fs::write(&env_file, "RUSTFLAGS=-Copt-level=3\n").unwrap();
+        std::fs::write(&asset, "v1").unwrap();
+        let included = vec![(asset.clone(), "GeoLite2.mmdb".to_owned())];
+        let state = |args: &[&str]| {
+            let mut all = vec!["cargo-aws-lambda", "func"];
+            all.extend_from_slice(args);
+            let opt = Opt::from_iter(&all);
+            hash_build_inputs(&dir, "x86_64-unknown-linux-musl", &opt, &included, "sha256:1")
+        };
+
+        let env_file_arg = env_file.to_string_lossy().into_owned();
+        let base = state(&["--env-file", &env_file_arg]);
+        let mut changed = vec![
+            state(&["--env-file", &env_file_arg, "--docker-arg=--network=host"]),
+            state(&["--env-file", &env_file_arg, "--docker-cmd", "make"]),
+            state(&["--env-file", &env_file_arg, "--glibc-version", "2.17"]),
+            state(&["--env-file", &env_file_arg, "--no-normalize-zip"]),
+        ];
+        std::fs::write(&env_file, "RUSTFLAGS=-Copt-level=s\n").unwrap();
+        changed.push(state(&["--env-file", &env_file_arg]));
+        std::fs::write(&env_file, "RUSTFLAGS=-Copt-level=3\n").unwrap();
+        std::fs::write(&asset, "v2").unwrap();
+        changed.push(state(&["--env-file", &env_file_arg]));
+        std::fs::remove_dir_all(&dir).ok();
+
+        for (i, state) in changed.iter().enumerate() {
+            assert_ne!(&base, state, "input {} didn't change the state", i);
+        }
+    }
+}
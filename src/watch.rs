@@ -0,0 +1,107 @@
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::env;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use crate::{exit, logs, util, Opt};
+use crate::util::CommandExt;
+
+/// Runs the deploy in a child process and again whenever the watched paths change. A failing
+/// build or deploy is reported and the loop goes on, Ctrl+C stops it.
+pub(crate) fn run(opt: &Opt, args: &[String]) {
+    if opt.tail_logs && opt.bin.len() > 1 {
+        eprintln!("--tail-logs needs a single binary");
+        process::exit(exit::BAD_ARGS);
+    }
+
+    let crate_dir = match opt.manifest_path {
+        Some(ref manifest_path) => util::project_dir_of(manifest_path),
+        None => env::current_dir().expect("Can't read cwd."),
+    };
+    let paths: Vec<PathBuf> = if opt.watch_paths.is_empty() {
+        vec![crate_dir.join("src"), crate_dir.join("Cargo.toml"), crate_dir.join("Cargo.lock")]
+    } else {
+        opt.watch_paths.iter().map(|path| crate_dir.join(path)).collect()
+    };
+
+    let result = ctrlc::set_handler(|| {
+        println!("\nStopped watching");
+        process::exit(0);
+    });
+    if let Err(e) = result {
+        eprintln!("Warning: can't handle Ctrl+C: {}", e);
+    }
+
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, Duration::from_millis(500)).unwrap_or_else(|e| {
+        eprintln!("Can't watch for changes: {}", e);
+        process::exit(exit::TOOLCHAIN);
+    });
+    for path in paths.iter().filter(|path| path.exists()) {
+        if let Err(e) = watcher.watch(path, RecursiveMode::Recursive) {
+            eprintln!("Warning: can't watch {}: {}", path.display(), e);
+        }
+    }
+
+    let exe = env::current_exe().expect("Can't find the cargo-aws-lambda executable");
+    let deploy_args = deploy_args(args);
+    thread::scope(|scope| {
+        let mut tailing = false;
+        loop {
+            let success = Command::new(&exe)
+                .arg("aws-lambda")
+                .args(&deploy_args)
+                .arg("--skip-unchanged")
+                .status_bool();
+            if !success {
+                eprintln!("\n===== Deploy failed, waiting for changes to try again =====");
+            } else if opt.tail_logs && !tailing {
+                tailing = true;
+                scope.spawn(|| {
+                    let (region, func_name) = util::parse_arn_or_key(&opt.arn);
+                    if let Err(e) = logs::tail(&opt.aws, &region, &func_name, &opt.tail) {
+                        eprintln!("Failed to tail logs:\n{:?}", e);
+                    }
+                });
+            }
+
+            let watched: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+            println!("\n===== Watching {} for changes =====", watched.join(", "));
+            wait_for_change(&rx);
+        }
+    });
+}
+
+/// Blocks until a debounced change comes in and drains the rest of the burst
+fn wait_for_change(rx: &std::sync::mpsc::Receiver<DebouncedEvent>) {
+    loop {
+        match rx.recv() {
+            Ok(DebouncedEvent::NoticeWrite(_)) | Ok(DebouncedEvent::NoticeRemove(_)) => {}
+            Ok(DebouncedEvent::Rescan) => {}
+            Ok(DebouncedEvent::Error(e, _)) => eprintln!("Warning: watching failed: {}", e),
+            Ok(_) => break,
+            Err(_) => process::exit(1),
+        }
+    }
+    while rx.try_recv().is_ok() {}
+}
+
+/// The command line without the program name, the watch options and the options the watch loop
+/// handles itself
+fn deploy_args(args: &[String]) -> Vec<String> {
+    let mut deploy_args = Vec::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--watch" | "--tail-logs" | "--skip-unchanged" => {}
+            "--watch-path" => {
+                args.next();
+            }
+            arg if arg.starts_with("--watch-path=") => {}
+            arg => deploy_args.push(arg.to_owned()),
+        }
+    }
+    deploy_args
+}